@@ -0,0 +1,83 @@
+use std::rc::Rc;
+
+// Define the nodes of the abstract syntax tree produced by the parser
+
+/// An expression and the columns (0-based, end exclusive) it spans in its line
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Expressions paired with a name, like keyword arguments or default values of parameters
+pub type NamedExprs = Vec<(String, Expr)>;
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Int(i64),
+    Number(f64),
+    Str(String),
+    List(Vec<Expr>),
+    Map(Vec<(Expr, Expr)>),
+    Variable(String),
+    Attribute(Box<Expr>, String),
+    Index {
+        target: Box<Expr>,
+        start: Option<Box<Expr>>,
+        end: Option<Box<Expr>>,
+        step: Option<Box<Expr>>,
+        slice: bool,
+    },
+    Call(Box<Expr>, Vec<Expr>, NamedExprs), // Positional and keyword arguments
+    BinaryOp(Box<Expr>, String, Box<Expr>),
+    UnaryOp(String, Box<Expr>),
+    FormatString(Vec<FormatPart>),
+    Lambda(Vec<String>, Box<Expr>), // Parameters and the expression that is returned
+}
+
+// A piece of an f-string
+#[derive(Debug, Clone)]
+pub enum FormatPart {
+    Text(String),
+    Expr(Expr, String), // The expression in braces and its format spec, empty if there is none
+}
+
+#[derive(Debug, Clone)]
+pub enum StmtKind {
+    Use(String),
+    Import(String),
+    If {
+        branches: Vec<(Expr, Vec<Stmt>)>,
+        else_body: Option<Vec<Stmt>>,
+    },
+    While(Expr, Vec<Stmt>),
+    For(String, Expr, Vec<Stmt>),
+    Break,
+    Continue,
+    Return(Option<Expr>),
+    Global(Vec<String>),
+    Nonlocal(Vec<String>),
+    Def {
+        name: String,
+        arguments: Vec<String>,
+        defaults: NamedExprs,
+        body: Rc<Vec<Stmt>>,
+    },
+    Class {
+        name: String,
+        base: Option<String>,
+        body: Vec<Stmt>,
+    },
+    Assign(Expr, Expr),
+    AugAssign(Expr, String, Expr), // Target, operator without the = and value, like x += 1
+    Expr(Expr),
+}
+
+/// A statement together with the line it was parsed from
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub line_number: usize,
+    pub line_content: String,
+}
@@ -1,6 +1,9 @@
-use crate::debug::EvaluatioError;
+use crate::debug::{ErrorKind, EvaluatioError, IplResult};
 use crate::value::Value;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
 use std::vec;
 
 // Define built-in functions and their argument names/ amounts
@@ -16,199 +19,240 @@ pub static BUILT_IN_FUNCTIONS: std::sync::LazyLock<HashMap<&str, Vec<&str>>> =
             ("round", vec!["number"]),
             ("pow", vec!["base", "exp"]),
             ("len", vec!["collection"]),
+            ("int", vec!["value"]),
+            ("float", vec!["value"]),
             ("quit", vec![]),
         ])
     });
 
-// Call a built-in function by name with given arguments
-pub fn call_built_in_function(name: &str, args: Vec<Value>) -> Value {
-    //println!("Called built in function {} with arguments {:?}", name, args);
-    if args.len() != BUILT_IN_FUNCTIONS[name].len() {
-        EvaluatioError::new(format!(
-            "Error: Function '{}' expects {} arguments, but got {}",
-            name,
-            BUILT_IN_FUNCTIONS[name].len(),
-            args.len()
-        ))
+/// A function written in Rust that was registered on an Evaluator
+#[derive(Clone)]
+pub struct NativeFunction {
+    pub arity: usize,
+    pub function: Rc<dyn Fn(Vec<Value>) -> IplResult<Value>>,
+}
+
+impl NativeFunction {
+    pub fn call(&self, name: &str, args: Vec<Value>) -> IplResult<Value> {
+        check_argument_count(name, self.arity, args.len())?;
+        (self.function)(args)
+    }
+}
+
+/// Where out() writes to and in() reads from, shared with the evaluators of libraries
+#[derive(Clone)]
+pub struct Streams {
+    pub output: Rc<RefCell<dyn Write>>,
+    pub input: Option<Rc<RefCell<dyn BufRead>>>, // None reads from stdin
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Self {
+            output: Rc::new(RefCell::new(io::stdout())),
+            input: None,
+        }
+    }
+}
+
+impl Streams {
+    fn write_line(&self, line: &str) -> IplResult<()> {
+        let mut output = self.output.borrow_mut();
+        writeln!(output, "{}", line)
+            .and_then(|_| output.flush())
+            .or_else(io_error)
+    }
+
+    fn read_line(&self) -> IplResult<String> {
+        let mut line = String::new();
+        let read = match &self.input {
+            Some(input) => input.borrow_mut().read_line(&mut line),
+            None => io::stdin().read_line(&mut line),
+        };
+        read.or_else(io_error)?;
+        Ok(line)
+    }
+}
+
+fn io_error<T>(error: io::Error) -> IplResult<T> {
+    EvaluatioError::new(ErrorKind::IO, error.to_string()).raise()
+}
+
+fn check_argument_count(name: &str, expected: usize, given: usize) -> IplResult<()> {
+    if given != expected {
+        return EvaluatioError::new(
+            ErrorKind::Argument,
+            format!(
+                "Function '{}' expects {} arguments, but got {}",
+                name, expected, given
+            ),
+        )
         .raise();
     }
+    Ok(())
+}
+
+fn type_error<T>(message: &str) -> IplResult<T> {
+    EvaluatioError::new(ErrorKind::Type, message.to_string()).raise()
+}
+
+fn conversion_error<T>(text: &str, type_name: &str) -> IplResult<T> {
+    EvaluatioError::new(
+        ErrorKind::Argument,
+        format!("Cannot convert '{}' to {}", text, type_name),
+    )
+    .raise()
+}
+
+// A whole float as an int, if it fits in one
+fn float_to_int(num: f64) -> IplResult<Value> {
+    if num >= i64::MIN as f64 && num < i64::MAX as f64 {
+        Ok(Value::Int(num as i64))
+    } else {
+        let message = if num.is_nan() {
+            "Cannot convert nan to an int".to_string()
+        } else {
+            format!("Float {:e} is too large in magnitude for an int", num)
+        };
+        EvaluatioError::new(ErrorKind::Overflow, message).raise()
+    }
+}
+
+// The error for an int result that is too large for an int
+pub fn overflow_error<T>(operation: &str) -> IplResult<T> {
+    EvaluatioError::new(
+        ErrorKind::Overflow,
+        format!("Integer result of {} is too large for an int", operation),
+    )
+    .raise()
+}
+
+pub fn division_by_zero_error<T>() -> IplResult<T> {
+    EvaluatioError::new(ErrorKind::Runtime, "Division by zero".to_string()).raise()
+}
+
+// A negative power of zero divides by zero
+pub fn is_zero_to_negative_power(base: &Value, exponent: &Value) -> bool {
+    base.is_number()
+        && *base == Value::Number(0.0)
+        && exponent.is_number()
+        && *exponent < Value::Number(0.0)
+}
+
+// Call a built-in function by name with given arguments
+pub fn call_built_in_function(
+    name: &str,
+    args: Vec<Value>,
+    streams: &Streams,
+) -> IplResult<Value> {
+    //println!("Called built in function {} with arguments {:?}", name, args);
+    check_argument_count(name, BUILT_IN_FUNCTIONS[name].len(), args.len())?;
     match name {
         "out" => {
-            if let Some(output) = args.first() {
-                println!("{}", output.to_string_value());
-            } else {
-                EvaluatioError::new("Error: 'out' function requires 1 argument".to_string())
-                    .raise();
-            }
-            Value::None
-        }
-        "value" => {
-            if let Some(number) = args.first() {
-                if let Value::Number(num) = number {
-                    return Value::Number(num.abs());
-                } else {
-                    EvaluatioError::new(
-                        "Error: 'value' function requires a numeric argument".to_string(),
-                    )
-                    .raise();
-                }
-            } else {
-                EvaluatioError::new("Error: 'value' function requires 1 argument".to_string())
-                    .raise();
-            }
-            Value::None
+            streams.write_line(&args[0].to_string_value())?;
+            Ok(Value::None)
         }
+        "value" => match &args[0] {
+            Value::Int(num) => match num.checked_abs() {
+                Some(abs) => Ok(Value::Int(abs)),
+                None => overflow_error("value"),
+            },
+            Value::Number(num) => Ok(Value::Number(num.abs())),
+            _ => type_error("'value' function requires a numeric argument"),
+        },
         "in" => {
-            use std::io::{self, Write};
-            if let Some(message) = args.first() {
-                println!("{}", message.to_string_value());
-                io::stdout().flush().unwrap();
-                let mut input: String = String::new();
-                io::stdin().read_line(&mut input).unwrap();
-                return Value::Str(input.trim().to_string());
-            } else {
-                EvaluatioError::new("Error: 'in' function requires 1 argument".to_string()).raise();
-            }
-            Value::None
+            streams.write_line(&args[0].to_string_value())?;
+            let input = streams.read_line()?;
+            Ok(Value::Str(input.trim().to_string()))
         }
         "random" => {
             use rand::Rng;
-            if args.len() == 2 {
-                if let (Value::Number(start), Value::Number(end)) = (&args[0], &args[1]) {
-                    let mut rng: rand::prelude::ThreadRng = rand::rng();
-                    return Value::Number(rng.random_range(*start as i32..=*end as i32) as f64);
-                } else {
-                    EvaluatioError::new(
-                        "Error: 'random' function requires two numeric arguments".to_string(),
+            if let (Some(start), Some(end)) = (args[0].as_number(), args[1].as_number()) {
+                if start > end {
+                    return EvaluatioError::new(
+                        ErrorKind::Argument,
+                        "'random' start must not be greater than end".to_string(),
                     )
                     .raise();
                 }
+                let mut rng: rand::prelude::ThreadRng = rand::rng();
+                Ok(Value::Int(rng.random_range(start as i64..=end as i64)))
             } else {
-                EvaluatioError::new("Error: 'random' function requires 2 arguments".to_string())
-                    .raise();
+                type_error("'random' function requires two numeric arguments")
             }
-            Value::None
         }
-        "min" => {
-            match args.first() {
-                Some(Value::List(list)) if !list.is_empty() => {
-                    let min_value = list
-                        .iter()
-                        .filter_map(|v| {
-                            if let Value::Number(num) = v {
-                                Some(*num)
-                            } else {
-                                None
-                            }
-                        })
-                        .min_by(|a, b| a.partial_cmp(b).unwrap());
-                    if let Some(min) = min_value {
-                        return Value::Number(min);
-                    } else {
-                        EvaluatioError::new(
-                            "Error: 'min' function requires a list of numeric values".to_string(),
-                        )
-                        .raise();
-                    }
-                }
-                _ => {
-                    EvaluatioError::new(
-                        "Error: 'min' function requires 1 argument which is a non-empty list"
-                            .to_string(),
-                    )
-                    .raise();
+        "min" => match &args[0] {
+            Value::List(list) if !list.is_empty() => {
+                let min_value = list
+                    .iter()
+                    .filter(|v| v.is_number())
+                    .min_by(|a, b| a.as_f64().total_cmp(&b.as_f64()));
+                match min_value {
+                    Some(min) => Ok(min.clone()),
+                    None => type_error("'min' function requires a list of numeric values"),
                 }
             }
-            Value::None
-        }
-        "max" => {
-            match args.first() {
-                Some(Value::List(list)) if !list.is_empty() => {
-                    let max_value = list
-                        .iter()
-                        .filter_map(|v| {
-                            if let Value::Number(num) = v {
-                                Some(*num)
-                            } else {
-                                None
-                            }
-                        })
-                        .max_by(|a, b| a.partial_cmp(b).unwrap());
-                    if let Some(max) = max_value {
-                        return Value::Number(max);
-                    } else {
-                        EvaluatioError::new(
-                            "Error: 'max' function requires a list of numeric values".to_string(),
-                        )
-                        .raise();
-                    }
-                }
-                _ => {
-                    EvaluatioError::new(
-                        "Error: 'max' function requires 1 argument which is a non-empty list"
-                            .to_string(),
-                    )
-                    .raise();
+            _ => type_error("'min' function requires 1 argument which is a non-empty list"),
+        },
+        "max" => match &args[0] {
+            Value::List(list) if !list.is_empty() => {
+                let max_value = list
+                    .iter()
+                    .filter(|v| v.is_number())
+                    .max_by(|a, b| a.as_f64().total_cmp(&b.as_f64()));
+                match max_value {
+                    Some(max) => Ok(max.clone()),
+                    None => type_error("'max' function requires a list of numeric values"),
                 }
             }
-            Value::None
-        }
-        "round" => {
-            if let Some(number) = args.first() {
-                if let Value::Number(num) = number {
-                    return Value::Number(num.round());
-                } else {
-                    EvaluatioError::new(
-                        "Error: 'round' function requires a numeric argument".to_string(),
-                    )
-                    .raise();
-                }
-            } else {
-                EvaluatioError::new("Error: 'round' function requires 1 argument".to_string())
-                    .raise();
+            _ => type_error("'max' function requires 1 argument which is a non-empty list"),
+        },
+        "round" => match &args[0] {
+            Value::Int(num) => Ok(Value::Int(*num)),
+            Value::Number(num) => float_to_int(num.round()),
+            _ => type_error("'round' function requires a numeric argument"),
+        },
+        "pow" if is_zero_to_negative_power(&args[0], &args[1]) => division_by_zero_error(),
+        "pow" => match args[0].clone().pow(args[1].clone()) {
+            Value::None if args.iter().all(|arg| matches!(arg, Value::Int(_))) => {
+                overflow_error("pow")
             }
-            Value::None
-        }
-        "pow" => {
-            if args.len() == 2 {
-                if let (Value::Number(base), Value::Number(exp)) = (&args[0], &args[1]) {
-                    return Value::Number(base.powf(*exp));
-                } else {
-                    EvaluatioError::new(
-                        "Error: 'pow' function requires two numeric arguments".to_string(),
-                    )
-                    .raise();
-                }
-            } else {
-                EvaluatioError::new("Error: 'pow' function requires 2 arguments".to_string())
-                    .raise();
-            }
-            Value::None
-        }
-        "len" => {
-            if let Some(collection) = args.first() {
-                match collection {
-                    Value::Str(s) => return Value::Number(s.chars().count() as f64),
-                    Value::List(l) => return Value::Number(l.len() as f64),
-                    _ => {
-                        EvaluatioError::new(
-                            "Error: 'len' function requires a string or list argument".to_string(),
-                        )
-                        .raise();
-                    }
-                }
-            } else {
-                EvaluatioError::new("Error: 'len' function requires 1 argument".to_string())
-                    .raise();
-            }
-            Value::None
-        }
-        "quit" => {
-            std::process::exit(0);
-        }
-        _ => {
-            EvaluatioError::new(format!("Error: Unknown built-in function '{}'", name)).raise();
-            Value::None
-        }
+            Value::None => type_error("'pow' function requires two numeric arguments"),
+            result => Ok(result),
+        },
+        "len" => match &args[0] {
+            Value::Str(s) => Ok(Value::Int(s.chars().count() as i64)),
+            Value::List(l) => Ok(Value::Int(l.len() as i64)),
+            Value::Map(m) => Ok(Value::Int(m.len() as i64)),
+            _ => type_error("'len' function requires a string, list or map argument"),
+        },
+        "int" => match &args[0] {
+            Value::Int(num) => Ok(Value::Int(*num)),
+            // Floats are truncated towards zero
+            Value::Number(num) => float_to_int(num.trunc()),
+            Value::Bool(b) => Ok(Value::Int(i64::from(*b))),
+            Value::Str(s) => match s.trim().parse::<i64>() {
+                Ok(num) => Ok(Value::Int(num)),
+                Err(_) => conversion_error(s, "int"),
+            },
+            _ => type_error("'int' function requires a number, bool or string argument"),
+        },
+        "float" => match &args[0] {
+            Value::Int(num) => Ok(Value::Number(*num as f64)),
+            Value::Number(num) => Ok(Value::Number(*num)),
+            Value::Bool(b) => Ok(Value::Number(f64::from(u8::from(*b)))),
+            Value::Str(s) => match s.trim().parse::<f64>() {
+                Ok(num) => Ok(Value::Number(num)),
+                Err(_) => conversion_error(s, "float"),
+            },
+            _ => type_error("'float' function requires a number, bool or string argument"),
+        },
+        // Unwind to whoever runs the program, the host decides how to exit
+        "quit" => EvaluatioError::new(ErrorKind::Quit, "quit() was called".to_string()).raise(),
+        _ => EvaluatioError::new(
+            ErrorKind::Name,
+            format!("Unknown built-in function '{}'", name),
+        )
+        .raise(),
     }
 }
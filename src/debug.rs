@@ -1,25 +1,162 @@
-use crate::state;
+use std::fmt;
 
+use crate::state::{self, Frame};
+
+/// The category of an error, shown in front of the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Syntax,
+    Name,
+    Type,
+    Index,
+    Key,
+    Argument,
+    Import,
+    IO,
+    Runtime,
+    Overflow,
+    Quit, // Raised by quit(), ends the program without printing anything
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorKind::Syntax => "SyntaxError",
+            ErrorKind::Name => "NameError",
+            ErrorKind::Type => "TypeError",
+            ErrorKind::Index => "IndexError",
+            ErrorKind::Key => "KeyError",
+            ErrorKind::Argument => "ArgumentError",
+            ErrorKind::Import => "ImportError",
+            ErrorKind::IO => "IOError",
+            ErrorKind::Runtime => "RuntimeError",
+            ErrorKind::Overflow => "OverflowError",
+            ErrorKind::Quit => "Quit",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct EvaluatioError {
-    message: String,
-    line_number: usize,
-    line_content: String,
+    pub kind: ErrorKind,
+    pub message: String,
+    pub file: String,
+    pub line_number: usize,
+    pub column: Option<usize>, // 1-based, like in editors
+    pub length: usize,
+    pub line_content: String,
+    pub traceback: Vec<Frame>,
 }
 
+pub type IplResult<T> = Result<T, Box<EvaluatioError>>;
+
 impl EvaluatioError {
-    // Create a new EvaluatioError, with message and optional line number and line content
-    pub fn new(message: String) -> Self {
+    // Create a new EvaluatioError at the line that is currently being evaluated
+    pub fn new(kind: ErrorKind, message: String) -> Self {
         Self {
+            kind,
             message,
+            file: state::get_file_path(),
             line_number: state::get_line_number(),
+            column: None,
+            length: 0,
             line_content: state::get_line_content(),
+            traceback: state::get_call_stack(),
         }
     }
 
-    pub fn raise(&self) {
-        panic!(
-            "Error: {} on line {:?}: {:?}",
-            self.message, self.line_number, self.line_content
-        );
+    // Point the error at the columns (0-based, end exclusive) of the offending code
+    pub fn at(mut self, start: usize, end: usize) -> Self {
+        self.column = Some(start + 1);
+        self.length = end.saturating_sub(start).max(1);
+        self
+    }
+
+    // Return the error, so it can be used like `return EvaluatioError::new(..).raise();`
+    pub fn raise<T>(self) -> IplResult<T> {
+        Err(Box::new(self))
+    }
+}
+
+impl fmt::Display for EvaluatioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only show the traceback when the error happened inside a function
+        if self.traceback.len() > 1 {
+            writeln!(f, "Traceback (most recent call last):")?;
+            for frame in &self.traceback {
+                writeln!(
+                    f,
+                    "  {}:{} in {}: {}",
+                    frame.file_path,
+                    frame.line_number,
+                    frame.describe(),
+                    first_line(&frame.line_content).trim()
+                )?;
+            }
+        }
+        write!(f, "{}: {}", self.kind, self.message)?;
+        if self.line_number == 0 {
+            return Ok(());
+        }
+        // A statement with a triple-quoted string spans several lines, show the one with the column
+        let (line_offset, line_content, column) = match self.column {
+            Some(column) => {
+                let (offset, line, column) = physical_line(&self.line_content, column);
+                (offset, line, Some(column))
+            }
+            None => (0, first_line(&self.line_content), None),
+        };
+        let line_number = self.line_number + line_offset;
+        write!(f, "\n  --> {}:{}", self.file, line_number)?;
+        if let Some(column) = column {
+            write!(f, ":{}", column)?;
+        }
+
+        // Render the line with the offending part underlined
+        let gutter = " ".repeat(line_number.to_string().len());
+        write!(f, "\n{} |", gutter)?;
+        write!(f, "\n{} | {}", line_number, line_content)?;
+        let indentation = line_content.len() - line_content.trim_start().len();
+        let (start, length) = match column {
+            Some(column) => {
+                // Underline at most to the end of the shown line
+                let rest = line_content.chars().count().saturating_sub(column - 1);
+                (column - 1, self.length.min(rest))
+            }
+            None => (
+                line_content[..indentation].chars().count(),
+                line_content.trim().chars().count(),
+            ),
+        };
+        // Keep tabs, so the underline lines up with the code above it
+        let padding: String = line_content
+            .chars()
+            .chain(std::iter::repeat(' '))
+            .take(start)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "\n{} | {}{}", gutter, padding, "^".repeat(length.max(1)))
+    }
+}
+
+impl std::error::Error for EvaluatioError {}
+
+fn first_line(content: &str) -> &str {
+    content.lines().next().unwrap_or("")
+}
+
+// Find the physical line a 1-based column of a joined line is on,
+// returning how many lines it is after the first, the line and the column in it
+fn physical_line(content: &str, column: usize) -> (usize, &str, usize) {
+    let mut column = column;
+    let mut lines = content.split('\n').enumerate().peekable();
+    while let Some((offset, line)) = lines.next() {
+        let length = line.chars().count();
+        if column <= length + 1 || lines.peek().is_none() {
+            return (offset, line, column);
+        }
+        column -= length + 1; // The newline counts as a character
     }
+    (0, "", column)
 }
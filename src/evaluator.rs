@@ -1,42 +1,373 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::io::{BufRead, Write};
 use std::path::PathBuf;
-use std::vec;
+use std::rc::Rc;
 
+use crate::ast::{Expr, ExprKind, FormatPart, Stmt, StmtKind};
 use crate::built_in_functions::BUILT_IN_FUNCTIONS;
-use crate::built_in_functions::call_built_in_function;
-use crate::debug::EvaluatioError;
+use crate::built_in_functions::{
+    NativeFunction, Streams, call_built_in_function, division_by_zero_error,
+    is_zero_to_negative_power, overflow_error,
+};
+use crate::debug::{ErrorKind, EvaluatioError, IplResult};
 use crate::library::get_library_entry_path;
+use crate::parser::Parser;
 use crate::state;
-use crate::tokenizer::Tokenizer;
-use crate::value::{ClassStr, Value, Class, Instance, IPL_Library};
+use crate::value::{
+    Callable, ClassStr, Value, Class, Environment, Function, Instance, IPL_Library,
+};
+
+/// Deepest nesting of function calls before a RuntimeError is raised,
+/// the binary runs on a stack large enough for it
+const MAX_CALL_DEPTH: usize = 1000;
+
+/// Keyword arguments of a call in the order they were written
+type Keywords = Vec<(String, Value)>;
+
+/// What happened when executing a block, so loops and functions know how to continue
+enum Flow {
+    Normal,
+    Break,
+    Continue,
+    Return(Value),
+}
+
+/// Local variables of a running function
+#[derive(Default)]
+struct Scope {
+    environment: Rc<Environment>,
+    globals: HashSet<String>,   // Names declared with global
+    nonlocals: HashSet<String>, // Names declared with nonlocal
+}
+
+// Match the arguments of a call to the parameters of a function, filling in default values
+fn bind_arguments(
+    function: &Function,
+    args: Vec<Value>,
+    keywords: Keywords,
+) -> IplResult<HashMap<String, Value>> {
+    let parameters = &function.arguments;
+    let required = parameters.len() - function.defaults.len();
+    if args.len() > parameters.len() || (keywords.is_empty() && args.len() < required) {
+        let expected = if function.defaults.is_empty() {
+            parameters.len().to_string()
+        } else {
+            format!("{} to {}", required, parameters.len())
+        };
+        return EvaluatioError::new(
+            ErrorKind::Argument,
+            format!("Wrong amount of arguments, expected {} but got {}", expected, args.len()),
+        )
+        .raise();
+    }
+
+    let mut variables: HashMap<String, Value> = parameters.iter().cloned().zip(args).collect();
+    for (name, value) in keywords {
+        let message = if !parameters.contains(&name) {
+            format!("{}() got an unknown keyword argument '{}'", function.name, name)
+        } else {
+            match variables.entry(name) {
+                Entry::Vacant(entry) => {
+                    entry.insert(value);
+                    continue;
+                }
+                Entry::Occupied(entry) => format!(
+                    "{}() got multiple values for argument '{}'",
+                    function.name,
+                    entry.key()
+                ),
+            }
+        };
+        return EvaluatioError::new(ErrorKind::Argument, message).raise();
+    }
+    for name in parameters {
+        if variables.contains_key(name) {
+            continue;
+        }
+        match function.defaults.get(name) {
+            Some(value) => variables.insert(name.clone(), value.clone()),
+            None => {
+                return EvaluatioError::new(
+                    ErrorKind::Argument,
+                    format!("Missing argument '{}' for {}()", name, function.name),
+                )
+                .raise();
+            }
+        };
+    }
+    Ok(variables)
+}
+
+// Attach the columns of an expression to errors that do not point anywhere yet
+fn point_at(expr: &Expr) -> impl FnOnce(Box<EvaluatioError>) -> Box<EvaluatioError> {
+    move |mut error| {
+        // Errors from inside a called function already point at their own line
+        if error.column.is_none() && error.traceback.len() == state::get_call_depth() {
+            *error = error.at(expr.start, expr.end);
+        }
+        error
+    }
+}
+
+/// An evaluated index or slice (start, stop, step) of a sequence
+enum Selection {
+    Index(usize),
+    Slice(i64, i64, i64),
+}
+
+/// Where the value of an assignment target is stored
+enum Target {
+    Variable(String),
+    ClassVariable(String, String), // Class name and variable name
+    Attribute(Rc<RefCell<Instance>>, String),
+    Temporary(Value), // The result of any other expression, only read
+}
+
+/// An evaluated map key, or index or slice of a list
+enum Step {
+    Key(Value),
+    Items(Selection),
+}
+
+/// An assignment target with its object and indices already evaluated.
+/// Items are read and changed where they are stored, their containers are not copied
+struct Place {
+    target: Target,
+    steps: Vec<Step>,
+}
+
+impl Place {
+    fn new(target: Target) -> Self {
+        Self {
+            target,
+            steps: vec![],
+        }
+    }
+}
+
+/// What a place holds, to know how the [] after it are evaluated
+enum Shape {
+    Map,
+    List(usize),
+    Other,
+}
+
+impl Shape {
+    fn of(value: &Value) -> Self {
+        match value {
+            Value::Map(_) => Shape::Map,
+            Value::List(list) => Shape::List(list.len()),
+            _ => Shape::Other,
+        }
+    }
+}
+
+// Follow the steps of a place down from the value stored at its target.
+// The items of a slice are a new list, everything else is borrowed
+fn find_item<'a>(value: &'a Value, steps: &[Step]) -> IplResult<Cow<'a, Value>> {
+    let Some((step, rest)) = steps.split_first() else {
+        return Ok(Cow::Borrowed(value));
+    };
+    let item = match (value, step) {
+        (Value::Map(_), Step::Key(key)) => match value.get_entry(key) {
+            Some(item) => item,
+            None => return key_error(key),
+        },
+        (Value::List(list), Step::Items(Selection::Index(index))) => match list.get(*index) {
+            Some(item) => item,
+            None => return index_error(*index, list.len()),
+        },
+        (Value::List(list), Step::Items(Selection::Slice(start, stop, step))) => {
+            check_slice(list.len(), *start, *stop, *step)?;
+            let items = slice_positions(*start, *stop, *step)
+                .map(|index| list[index].clone())
+                .collect();
+            return Ok(Cow::Owned(find_item(&Value::List(items), rest)?.into_owned()));
+        }
+        _ => return changed_container_error(value),
+    };
+    find_item(item, rest)
+}
+
+// Write a value to the item at the end of the steps, inside the value of the target
+fn replace_item(value: &mut Value, steps: &[Step], new_value: Value) -> IplResult<()> {
+    let Some((step, rest)) = steps.split_first() else {
+        *value = new_value;
+        return Ok(());
+    };
+    match (value, step) {
+        (map @ Value::Map(_), Step::Key(key)) if rest.is_empty() => {
+            map.set_entry(key.clone(), new_value);
+            Ok(())
+        }
+        (map @ Value::Map(_), Step::Key(key)) => match map.get_entry_mut(key) {
+            Some(item) => replace_item(item, rest, new_value),
+            None => key_error(key),
+        },
+        (Value::List(list), Step::Items(Selection::Index(index))) => {
+            let length = list.len();
+            match list.get_mut(*index) {
+                Some(item) => replace_item(item, rest, new_value),
+                None => index_error(*index, length),
+            }
+        }
+        (Value::List(list), Step::Items(Selection::Slice(start, stop, step))) => {
+            check_slice(list.len(), *start, *stop, *step)?;
+            if rest.is_empty() {
+                return assign_slice(list, *start, *stop, *step, new_value);
+            }
+            // Items inside a slice are changed in a copy of the slice that is put back
+            let mut items = Value::List(
+                slice_positions(*start, *stop, *step)
+                    .map(|index| list[index].clone())
+                    .collect(),
+            );
+            replace_item(&mut items, rest, new_value)?;
+            assign_slice(list, *start, *stop, *step, items)
+        }
+        (value, _) => changed_container_error(value),
+    }
+}
+
+fn key_error<T>(key: &Value) -> IplResult<T> {
+    EvaluatioError::new(
+        ErrorKind::Key,
+        format!("Key {} not found", key.to_string_value()),
+    )
+    .raise()
+}
+
+fn index_error<T>(index: usize, length: usize) -> IplResult<T> {
+    EvaluatioError::new(
+        ErrorKind::Index,
+        format!("Index {} is out of bounds for length {}", index, length),
+    )
+    .raise()
+}
+
+// A slice that was evaluated for a list which has since become shorter
+fn check_slice(length: usize, start: i64, stop: i64, step: i64) -> IplResult<()> {
+    let end = if step > 0 {
+        stop.max(start)
+    } else if start > stop {
+        start + 1
+    } else {
+        0
+    };
+    if end as usize > length {
+        return EvaluatioError::new(
+            ErrorKind::Index,
+            format!("Slice is out of bounds for length {}", length),
+        )
+        .raise();
+    }
+    Ok(())
+}
+
+// The indices of an assignment target were evaluated for a container that was replaced since
+fn changed_container_error<T>(value: &Value) -> IplResult<T> {
+    EvaluatioError::new(
+        ErrorKind::Type,
+        format!("Cannot index {}", value.type_name_with_article()),
+    )
+    .raise()
+}
+
+// The positions a slice selects, the stop is exclusive
+fn slice_positions(start: i64, stop: i64, step: i64) -> impl Iterator<Item = usize> {
+    let mut next = Some(start);
+    std::iter::from_fn(move || {
+        let position = next?;
+        let inside = if step > 0 { position < stop } else { position > stop };
+        if !inside {
+            return None;
+        }
+        // A step that goes past the end of i64 also goes past the stop
+        next = position.checked_add(step);
+        Some(position as usize)
+    })
+}
 
+// Replace the elements selected by a slice with the elements of a list
+fn assign_slice(
+    list: &mut Vec<Value>,
+    start: i64,
+    stop: i64,
+    step: i64,
+    value: Value,
+) -> IplResult<()> {
+    let Value::List(items) = value else {
+        return EvaluatioError::new(
+            ErrorKind::Type,
+            format!("Cannot assign {} to a slice", value.type_name_with_article()),
+        )
+        .raise();
+    };
+    if step == 1 {
+        // The new elements can have another length
+        list.splice(start as usize..stop.max(start) as usize, items);
+        return Ok(());
+    }
+    let positions: Vec<usize> = slice_positions(start, stop, step).collect();
+    if positions.len() != items.len() {
+        return EvaluatioError::new(
+            ErrorKind::Index,
+            format!(
+                "Cannot assign {} elements to a slice of {} elements",
+                items.len(),
+                positions.len()
+            ),
+        )
+        .raise();
+    }
+    for (position, item) in positions.into_iter().zip(items) {
+        list[position] = item;
+    }
+    Ok(())
+}
+
+// Only numbers, strings and bools can be used as map keys
+fn check_map_key(key: &Value) -> IplResult<()> {
+    if key.is_number() || key.is_string() || key.is_bool() {
+        return Ok(());
+    }
+    EvaluatioError::new(
+        ErrorKind::Type,
+        format!("Cannot use {} as a map key", key.type_name_with_article()),
+    )
+    .raise()
+}
 
 // Define the Evaluator struct and its methods for evaluating IPL code
 pub struct Evaluator {
-    files: HashMap<String, Vec<String>>,
-    lines: Vec<String>,
-    pub variables: HashMap<String, Value>,
-    pub functions: HashMap<String, HashMap<String, Value>>,
-    pub classes: HashMap<String, Class>,
-    pub ipl_libraries: HashMap<String, IPL_Library>,
+    variables: HashMap<String, Value>,
+    functions: HashMap<String, Function>,
+    classes: HashMap<String, Class>,
+    ipl_libraries: HashMap<String, IPL_Library>,
     evaluators: HashMap<String, Evaluator>,
-    indentation_stack: Vec<(String, usize)>,
+    parent: Option<Box<Evaluator>>, // The evaluator that called into this library, while it runs
+    scopes: Vec<Scope>, // One per running function, empty at the top level
+    native_functions: HashMap<String, NativeFunction>,
+    streams: Streams,
 
-    tokenizer: Tokenizer,
+    lib_name: String,
     folder: String,
     path: PathBuf,
 }
 
-fn get_indentation(line: &str) -> usize {
-    line.chars().take_while(|c| c.is_whitespace()).count()
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Evaluator {
     pub fn new() -> Self {
         Self {
-            files: HashMap::new(),
-            lines: vec![],
             variables: HashMap::from([
                 ("True".to_string(), Value::Bool(true)),
                 ("False".to_string(), Value::Bool(false)),
@@ -47,19 +378,31 @@ impl Evaluator {
             ]),
             functions: HashMap::new(),
             evaluators: HashMap::new(),
+            parent: None,
+            scopes: vec![],
+            native_functions: HashMap::new(),
+            streams: Streams::default(),
             ipl_libraries: HashMap::new(),
             classes: HashMap::new(),
-            indentation_stack: vec![],
 
-            tokenizer: Tokenizer::new(),
+            lib_name: String::new(),
             folder: String::new(),
             path: PathBuf::new(),
         }
     }
 
-    // Evaluate a file by reading its contents and executing its lines
-    pub fn ev_file(&mut self, file: &str) {
+    /// Read a global variable
+    pub fn get_variable(&self, name: &str) -> Option<&Value> {
+        self.variables.get(name)
+    }
+
+    /// Create or overwrite a global variable
+    pub fn set_variable(&mut self, name: &str, value: Value) {
+        self.variables.insert(name.to_string(), value);
+    }
 
+    // Evaluate a file by parsing its contents and executing the statements
+    pub fn ev_file(&mut self, file: &str) -> IplResult<()> {
         let path: PathBuf = PathBuf::from(file); // Convert file string to PathBuf
         self.folder = path
             .parent()
@@ -67,1041 +410,1371 @@ impl Evaluator {
             .unwrap_or("")
             .to_string();
         self.folder += "//"; // Get the folder path for imports
-        let contents = fs::read_to_string(file).expect("Could not read file"); // Read file contents
-
-        self.lines = contents
-            .lines()
-            .map(|line| line.to_string())
-            .filter(|line| !line.trim().is_empty())
-            .collect();
-
-        self.lines.push("End of file".to_string()); // Add end marker to lines
+        let contents = match fs::read_to_string(file) {
+            Ok(contents) => contents,
+            Err(error) => {
+                return EvaluatioError::new(
+                    ErrorKind::IO,
+                    format!("Could not read file {}: {}", file, error),
+                )
+                .raise();
+            }
+        };
 
-        let file_path = path.to_str().unwrap_or("").to_string();
-        self.files.insert(file_path.clone(), self.lines.clone());
+        state::set_file_path(file);
+        let statements = Parser::new(&contents)?.parse_file()?;
         self.path = path;
 
-        self.indentation_stack = vec![("normal".to_string(), 0)]; // Initialize indentation stack
-
-        self.execute_lines(0, self.lines.len(), "".to_string(), &file_path); // Execute the file
+        let flow = self.execute_block(&statements, "")?;
+        self.check_top_level_flow(flow)
 
         // println!("variables {:#?}", self.variables);
         // println!("classes {:#?}", self.classes);
         // println!("functions {:#?}", self.functions);
     }
 
-    fn execute_lines(
-        &mut self,
-        start: usize,
-        end: usize,
-        self_value: String,
-        file_path: &String,
-    ) -> Value {
-        let mut programm_counter: usize = start;
-        // println!("execute_lines called with start {} and end {}", start, end);
+    /// Evaluate source code that is not in a file
+    /// Returns the value of the last statement if it is a bare expression
+    pub fn ev_source(&mut self, source: &str) -> IplResult<Value> {
+        self.ev_named_source("<string>", source)
+    }
 
-        self.lines = self.files[file_path].clone();
+    // Evaluate source code, using the name in place of a file name in errors
+    pub(crate) fn ev_named_source(&mut self, name: &str, source: &str) -> IplResult<Value> {
+        state::set_file_path(name);
+        // Functions defined here remember the name as their file for tracebacks
+        let path = std::mem::replace(&mut self.path, PathBuf::from(name));
+        let result = self.ev_source_statements(source);
+        self.path = path;
+        result
+    }
 
-        // println!("Execute lines from {} to {} in file {} with lines {:#?}", start, end, file_path, self.lines);
+    fn ev_source_statements(&mut self, source: &str) -> IplResult<Value> {
+        let statements = Parser::new(source)?.parse()?;
+        let Some((last, rest)) = statements.split_last() else {
+            return Ok(Value::None);
+        };
+        let flow = self.execute_block(rest, "")?;
+        self.check_top_level_flow(flow)?;
+        if let StmtKind::Expr(expr) = &last.kind {
+            state::set_programm_state(last.line_number, &last.line_content);
+            return self.ev_expr(expr);
+        }
+        let flow = self.execute_block(std::slice::from_ref(last), "")?;
+        self.check_top_level_flow(flow)?;
+        Ok(Value::None)
+    }
 
-        while programm_counter < end {
-            // println!("At line {}", programm_counter);
-            // println!("Indentation_stack: {:#?}", self.indentation_stack);
+    fn check_top_level_flow(&self, flow: Flow) -> IplResult<()> {
+        match flow {
+            Flow::Break => self.outside_loop_error("break"),
+            Flow::Continue => self.outside_loop_error("continue"),
+            Flow::Normal | Flow::Return(_) => Ok(()),
+        }
+    }
 
-            let mut line = self.lines[programm_counter].clone();
-            line = line.split("#").collect::<Vec<_>>()[0].to_string();
+    fn outside_loop_error<T>(&self, keyword: &str) -> IplResult<T> {
+        EvaluatioError::new(ErrorKind::Syntax, format!("'{}' outside loop", keyword)).raise()
+    }
 
-            // println!("Current line: '{}'", line);
+    // Execute statements in order, stopping early on break, continue or return
+    fn execute_block(&mut self, body: &[Stmt], self_value: &str) -> IplResult<Flow> {
+        for statement in body {
+            state::set_programm_state(statement.line_number, &statement.line_content);
+            let flow = self.execute_statement(statement, self_value)?;
+            if !matches!(flow, Flow::Normal) {
+                return Ok(flow);
+            }
+        }
+        Ok(Flow::Normal)
+    }
 
-            state::set_programm_state(programm_counter, &line);
+    fn execute_statement(&mut self, statement: &Stmt, self_value: &str) -> IplResult<Flow> {
+        match &statement.kind {
+            StmtKind::Use(lib_name) => {
+                if self.ipl_libraries.contains_key(lib_name) {
+                    return Ok(Flow::Normal);
+                }
+                let lib_path = get_library_entry_path(lib_name)?
+                    .to_string_lossy()
+                    .to_string();
+                let mut evaluator = Evaluator::new();
+                evaluator.lib_name = lib_name.to_string();
+                evaluator.native_functions = self.native_functions.clone();
+                evaluator.streams = self.streams.clone();
+                state::push_frame("<module>", "", lib_name, &lib_path);
+                let result = evaluator.ev_file(&lib_path);
+                state::pop_frame();
+                result?;
+                let ipl_lib = IPL_Library {
+                    lib_name: lib_name.to_string(),
+                    functions: evaluator.functions.clone(),
+                    variables: evaluator.variables.clone(),
+                    classes: evaluator.classes.clone(),
+                };
+                self.evaluators.insert(lib_name.to_string(), evaluator);
+                self.ipl_libraries.insert(lib_name.to_string(), ipl_lib);
+            }
+            StmtKind::Import(name) => {
+                let file = self.folder.clone() + name + ".ipl";
 
-            let indentation = get_indentation(&line);
+                let folder = self.folder.clone();
+                let path = self.path.clone();
 
-            line = line.trim().to_string();
+                let result = self.ev_file(&file);
 
-            // println!("Indentation_stack: {:?}", self.indentation_stack);
-            if indentation
-                <= self
-                    .indentation_stack
-                    .last()
-                    .unwrap_or(&("".to_string(), 0))
-                    .1
-            {
-                if self.indentation_stack.last().is_none() {
-                    return Value::None;
-                }
-                if self.indentation_stack[self.indentation_stack.len() - 1].0 == "while" {
-                    while self.lines[programm_counter].split(" ").collect::<Vec<_>>()[0] != "while"
-                    {
-                        programm_counter -= 1;
+                self.folder = folder;
+                self.path = path;
+                result?;
+                state::set_file_path(&self.path.to_string_lossy());
+            }
+            StmtKind::If {
+                branches,
+                else_body,
+            } => {
+                for (condition, body) in branches {
+                    if self.ev_expr(condition)?.as_bool() {
+                        return self.execute_block(body, self_value);
                     }
-                    self.indentation_stack.pop();
-                    continue;
                 }
-            } else if self.indentation_stack[self.indentation_stack.len() - 1].0 == "if"
-                || self.indentation_stack[self.indentation_stack.len() - 1].0 == "else"
-            {
-                self.indentation_stack.pop();
+                if let Some(body) = else_body {
+                    return self.execute_block(body, self_value);
+                }
             }
-            match line.split(" ").collect::<Vec<_>>()[0] {
-                "use" => {
-                    let lib_name = line.split(" ").collect::<Vec<_>>()[1];
-                    if self.ipl_libraries.contains_key(lib_name) {
-                        programm_counter += 1;
-                        continue;
+            StmtKind::While(condition, body) => {
+                while self.ev_expr(condition)?.as_bool() {
+                    match self.execute_block(body, self_value)? {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Normal | Flow::Continue => {}
                     }
-                    let lib_path = get_library_entry_path(lib_name)
-                        .to_str()
-                        .unwrap()
-                        .to_string();
-                    self.evaluators.insert(lib_path.clone(), Evaluator::new());
-                    if let Some(evaluator) = self.evaluators.get_mut(&lib_path) {
-                        evaluator.ev_file(&lib_path);
+                }
+            }
+            StmtKind::For(variable_name, iterable, body) => {
+                let value = self.ev_expr(iterable)?;
+                let Some(values) = value.iter() else {
+                    return EvaluatioError::new(
+                        ErrorKind::Type,
+                        format!("Cannot iterate over {}", value.type_name_with_article()),
+                    )
+                    .at(iterable.start, iterable.end)
+                    .raise();
+                };
+                for value in values {
+                    self.set_variable_in_scope(variable_name, value);
+                    match self.execute_block(body, self_value)? {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Normal | Flow::Continue => {}
                     }
-                    let ipl_lib = IPL_Library {
-                        lib_name: lib_name.to_string(),
-                        functions: self.evaluators[&lib_path].functions.clone(),
-                        variables: self.evaluators[&lib_path].variables.clone(),
-                        classes: self.evaluators[&lib_path].classes.clone(),
-                    };
-
-                    self.ipl_libraries.insert(lib_name.to_string(), ipl_lib);
-
-                    programm_counter += 1;
                 }
-                "import" => {
-                    let file = self.folder.clone() + line.split(" ").collect::<Vec<_>>()[1] + ".ipl";
-
-                    let folder = self.folder.clone();
-                    let path = self.path.clone();
-                    let indentation_stack = self.indentation_stack.clone();
-                    let lines = self.lines.clone();
-
-                    self.ev_file(&file);
-
-                    self.folder = folder;
-                    self.path = path;
-                    self.indentation_stack = indentation_stack;
-                    self.lines = lines;
-
-                    // println!("self.files after import: {:#?}", self.files);
-                    // println!("self.functions after import: {:#?}", self.functions);
-                    // println!("self.variables after import: {:#?}", self.variables);
-
-                    programm_counter += 1;
+            }
+            StmtKind::Break => return Ok(Flow::Break),
+            StmtKind::Continue => return Ok(Flow::Continue),
+            StmtKind::Return(expr) => {
+                let value = match expr {
+                    Some(expr) => self.ev_expr(expr)?,
+                    None => Value::None,
+                };
+                return Ok(Flow::Return(value));
+            }
+            StmtKind::Global(names) => {
+                // At the top level every variable is global already
+                if let Some(scope) = self.scopes.last_mut() {
+                    for name in names {
+                        scope.environment.variables.borrow_mut().remove(name);
+                        scope.globals.insert(name.to_string());
+                    }
                 }
-                "while" => {
-                    let mut result = false;
-                    if let Some((_first, rest)) = line.split_once(' ') {
-                        result = self.ev_expr(rest).as_bool();
+            }
+            StmtKind::Nonlocal(names) => {
+                let Some(scope) = self.scopes.last_mut() else {
+                    return EvaluatioError::new(
+                        ErrorKind::Syntax,
+                        "nonlocal used outside a function".to_string(),
+                    )
+                    .raise();
+                };
+                for name in names {
+                    if scope.environment.find_enclosing(name).is_none() {
+                        return EvaluatioError::new(
+                            ErrorKind::Syntax,
+                            format!("No variable {} in an enclosing function", name),
+                        )
+                        .raise();
                     }
-                    if result {
-                        programm_counter += 1;
-                        self.indentation_stack
-                            .push(("while".to_string(), indentation));
-                    } else {
-                        programm_counter += 1;
-                        while get_indentation(&self.lines[programm_counter].clone())
-                            > self.indentation_stack[self.indentation_stack.len() - 1].1
-                        {
-                            programm_counter += 1
+                    scope.environment.variables.borrow_mut().remove(name);
+                    scope.nonlocals.insert(name.to_string());
+                }
+            }
+            StmtKind::Def {
+                name,
+                arguments,
+                defaults,
+                body,
+            } => {
+                // Default values are evaluated once, when the function is defined
+                let mut default_values = HashMap::new();
+                for (parameter, expr) in defaults {
+                    default_values.insert(parameter.to_string(), self.ev_expr(expr)?);
+                }
+                let closure = self.scopes.last().map(|scope| Rc::clone(&scope.environment));
+                let function = Function {
+                    name: name.to_string(),
+                    file: self.path.clone(),
+                    arguments: arguments.clone(),
+                    defaults: default_values,
+                    body: body.clone(),
+                    closure: closure.clone(),
+                    lib_name: self.lib_name.clone(),
+                };
+                if closure.is_some() {
+                    // A function defined inside a function is a local variable of it
+                    let value = Value::Function(Box::new(Callable::Function(function)));
+                    self.set_variable_in_scope(name, value);
+                } else {
+                    self.functions.insert(name.to_string(), function);
+                }
+            }
+            StmtKind::Class { name, base, body } => {
+                let mut class = Class {
+                    functions: HashMap::new(),
+                    variables: HashMap::new(),
+                };
+                if let Some(base) = base {
+                    match self.classes.get(base) {
+                        Some(base_class) => class = base_class.clone(),
+                        None => {
+                            return EvaluatioError::new(
+                                ErrorKind::Name,
+                                format!("Base class {} not found", base),
+                            )
+                            .raise();
                         }
                     }
                 }
-                "for" => {
-                    let variable_name = line.split(" ").collect::<Vec<_>>()[1];
-                    let iterable_expr = line.split("in").collect::<Vec<_>>()[1].trim();
-                    // println!("For loop variable: {}, iterable expression: {}", variable_name, iterable_expr);
-                    let iterable = self.ev_expr(iterable_expr);
-                    // println!("Iterable evaluated to: {:?}", iterable);
-                    let start_line = programm_counter + 1;
-                    let mut end_line = start_line;
-                    while get_indentation(&self.lines[end_line]) > indentation {
-                        end_line += 1;
-                    }
+                self.classes.insert(name.to_string(), class);
+
+                // The class body runs like top level code, so its functions become methods
+                let funcs = std::mem::take(&mut self.functions);
+                let scopes = std::mem::take(&mut self.scopes);
+                let result = self.execute_block(body, name);
+                self.scopes = scopes;
+                let class_functions = std::mem::replace(&mut self.functions, funcs);
+                result?;
+
+                self.classes
+                    .get_mut(name)
+                    .unwrap()
+                    .functions
+                    .extend(class_functions);
+            }
+            StmtKind::Assign(target, expr) => {
+                let value = self.ev_expr(expr)?;
+                self.assign(target, value, self_value).map_err(point_at(target))?;
+            }
+            StmtKind::AugAssign(target, operator, expr) => {
+                self.augmented_assign(target, operator, expr, self_value)?;
+            }
+            StmtKind::Expr(expr) => {
+                self.ev_expr(expr)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
 
-                    self.indentation_stack
-                        .push(("for".to_string(), indentation));
-                    for value in iterable.iter() {
-                        self.variables
-                            .insert(variable_name.to_string(), value.clone());
-                        let output = self.execute_lines(start_line, end_line, "".to_string(), file_path);
-                        if output.to_string_value() == "break"{
-                            break
-                        }
+    // Store a value in a variable, or in a field of an instance or class
+    fn assign(&mut self, target: &Expr, value: Value, self_value: &str) -> IplResult<()> {
+        let place = self.resolve_place(target, self_value)?;
+        self.store(place, value)
+    }
+
+    // Assign like x += 1, the target is evaluated once for reading and writing
+    fn augmented_assign(
+        &mut self,
+        target: &Expr,
+        operator: &str,
+        expr: &Expr,
+        self_value: &str,
+    ) -> IplResult<()> {
+        let mut place = self.resolve_place(target, self_value).map_err(point_at(target))?;
+        let current = self.load(&mut place).map_err(point_at(target))?;
+        let value = self.ev_expr(expr)?;
+        let value = self.ev_binary_op(current, operator, value)?;
+        self.store(place, value).map_err(point_at(target))
+    }
+
+    // Evaluate the object and the indices of an assignment target
+    fn resolve_place(&mut self, target: &Expr, self_value: &str) -> IplResult<Place> {
+        match &target.kind {
+            ExprKind::Variable(name) => Ok(Place::new(Target::Variable(name.to_string()))),
+            ExprKind::Attribute(object, var_name) => {
+                // In class bodies and static functions self is the class
+                if let ExprKind::Variable(name) = &object.kind
+                    && name == "self"
+                    && self_value != "self"
+                {
+                    if self_value.is_empty() {
+                        return EvaluatioError::new(
+                            ErrorKind::Name,
+                            "self used outside class".to_string(),
+                        )
+                        .raise();
                     }
-                    self.indentation_stack.pop();
-                    programm_counter = end_line;
+                    let target = Target::ClassVariable(self_value.to_string(), var_name.to_string());
+                    return Ok(Place::new(target));
                 }
-                "if" => {
-                    let mut result = false;
-                    if let Some((_first, rest)) = line.split_once(' ') {
-                        result = self.ev_expr(rest).as_bool();
+                let target = match self.ev_expr(object)? {
+                    Value::Instance(inst) => Target::Attribute(inst, var_name.to_string()),
+                    Value::ClassStr(class_str) if class_str.lib_name.is_empty() => {
+                        Target::ClassVariable(class_str.class_name, var_name.to_string())
                     }
-                    if result {
-                        programm_counter += 1;
-                        self.indentation_stack.push(("if".to_string(), indentation));
-                    } else {
-                        programm_counter += 1;
-                        while programm_counter < end {
-                            let current_line = self.lines[programm_counter].clone();
-                            let current_indent = get_indentation(&current_line);
-                            let first_word = current_line.split_whitespace().next().unwrap_or("");
-
-                            state::set_programm_state(programm_counter, &current_line);
-
-                            if current_indent > indentation {
-                                programm_counter += 1;
-                                continue;
-                            }
-                            if first_word == "elif" {
-                                if let Some((_first, rest)) = current_line.split_once(' ') {
-                                    if self.ev_expr(rest).as_bool() {
-                                        programm_counter += 1;
-                                        self.indentation_stack
-                                            .push(("if".to_string(), indentation));
-                                        break;
-                                    } else {
-                                        // Skip block
-                                        programm_counter += 1;
-                                        while programm_counter < end
-                                            && get_indentation(&self.lines[programm_counter])
-                                                > indentation
-                                        {
-                                            programm_counter += 1;
-                                        }
-                                    }
-                                }
-                            } else if first_word == "else" {
-                                programm_counter += 1;
-                                self.indentation_stack
-                                    .push(("else".to_string(), indentation));
-                                break;
-                            } else if current_indent <= indentation {
-                                break;
-                            } else {
-                                programm_counter += 1;
-                                break;
-                            }
-                        }
+                    other => {
+                        return EvaluatioError::new(
+                            ErrorKind::Type,
+                            format!("Cannot set attributes on {}", other.type_name_with_article()),
+                        )
+                        .raise();
                     }
-                }
-                "else" => {
-                    programm_counter += 1;
-                    while get_indentation(&self.lines[programm_counter].clone())
-                        > self.indentation_stack[self.indentation_stack.len() - 1].1
-                    {
-                        programm_counter += 1;
+                };
+                Ok(Place::new(target))
+            }
+            ExprKind::Index {
+                target: container_expr,
+                start,
+                end,
+                step,
+                slice,
+            } => {
+                let mut container = self.resolve_place(container_expr, self_value)?;
+                match self.with_place(&mut container, |value| Ok(Shape::of(value)))? {
+                    Shape::Map => {
+                        let key = self.ev_map_key(start, *slice)?;
+                        check_map_key(&key)?;
+                        container.steps.push(Step::Key(key));
                     }
-                }
-                "elif" => {
-                    programm_counter += 1;
-                    while get_indentation(&self.lines[programm_counter].clone())
-                        > self.indentation_stack[self.indentation_stack.len() - 1].1
-                    {
-                        programm_counter += 1;
+                    Shape::List(length) => {
+                        let selection = self.ev_selection(length, start, end, step, *slice)?;
+                        container.steps.push(Step::Items(selection));
                     }
-                }
-                "break" => {
-                    while let Some(x) = self.indentation_stack.pop() {
-                        if x.0 == "while" {
-                            while get_indentation(&self.lines[programm_counter]) > x.1 {
-                                programm_counter += 1;
-                            }
-                            break;
-                        } else if x.0 == "for" {
-                            return Value::Str("break".to_string()); // Signal to break for loop
-                        } else if x.0 == "normal" {
-                            EvaluatioError::new("Error: 'break' outside loop".to_string()).raise();
-                        }
+                    Shape::Other => {
+                        let type_name =
+                            self.with_place(&mut container, |value| Ok(value.type_name_with_article()))?;
+                        return EvaluatioError::new(
+                            ErrorKind::Type,
+                            format!("Cannot assign to items of {}", type_name),
+                        )
+                        .raise();
                     }
                 }
-                "continue" => {
-                    while let Some(x) = self.indentation_stack.pop() {
-                        if x.0 == "while" {
-                            while self.lines[programm_counter].split(" ").collect::<Vec<_>>()[0]
-                                != "while"
-                            {
-                                programm_counter -= 1;
-                            }
-                            break;
-                        } else if x.0 == "for" {
-                            self.indentation_stack.push(x); // Keep indentation (still in loop)
-                            return Value::None; // Stop execution of current iteration
-                        } else if x.0 == "normal" {
-                            EvaluatioError::new("Error: 'continue' outside loop".to_string())
-                                .raise();
-                        }
-                    }
+                Ok(container)
+            }
+            _ => EvaluatioError::new(
+                ErrorKind::Syntax,
+                "Cannot assign to this expression".to_string(),
+            )
+            .raise(),
+        }
+    }
+
+    // Run an access on the value stored at a target, without copying it
+    fn with_target<R>(
+        &mut self,
+        target: &mut Target,
+        access: impl FnOnce(&mut Value) -> IplResult<R>,
+    ) -> IplResult<R> {
+        let message = match target {
+            Target::Variable(name) => {
+                // Where get_variable_in_scope would find it
+                if let Some(scope) = self.scopes.last()
+                    && !scope.globals.contains(name.as_str())
+                    && let Some(environment) = scope.environment.holding(name)
+                    && let Some(value) = environment.variables.borrow_mut().get_mut(name.as_str())
+                {
+                    return access(value);
                 }
-                "return" => {
-                    let expr: &str = line.split("return").collect::<Vec<_>>()[1];
-                    return self.ev_expr(expr);
+                if let Some(value) = self.variables.get_mut(name.as_str()) {
+                    return access(value);
                 }
-                "class" => {
-                    let class_name: &str = line.split(" ").collect::<Vec<_>>()[1].trim();
-                    let base_class: &str = if line.contains(":") {
-                        line.split(":").collect::<Vec<_>>()[1].trim()
-                    } else {
-                        ""
-                    };
-                    let start_line = programm_counter + 1;
-                    let mut end_line = start_line;
-                    while get_indentation(&self.lines[end_line]) > indentation {
-                        end_line += 1;
-                    }
+                format!("Unknown variable {}", name)
+            }
+            Target::ClassVariable(class_name, name) => {
+                let class = self.classes.get_mut(class_name.as_str());
+                if let Some(value) = class.and_then(|class| class.variables.get_mut(name.as_str())) {
+                    return access(value);
+                }
+                format!("Class has no attribute {}", name)
+            }
+            Target::Attribute(inst, name) => {
+                if let Some(value) = inst.borrow_mut().variables.get_mut(name.as_str()) {
+                    return access(value);
+                }
+                format!("Instance has no attribute {}", name)
+            }
+            Target::Temporary(value) => return access(value),
+        };
+        EvaluatioError::new(ErrorKind::Name, message).raise()
+    }
 
-                    self.indentation_stack
-                        .push(("class".to_string(), indentation + 1));
-                    let funcs = self.functions.clone();
-                    self.functions.clear();
-
-                    self.classes.insert(
-                        class_name.to_string(),
-                        Class {
-                            functions: HashMap::new(),
-                            variables: HashMap::new(),
-                        },
-                    );
-
-                    if !base_class.is_empty() {
-                        self.classes.get_mut(class_name).unwrap().variables =
-                            self.classes[base_class].variables.clone();
-                        self.classes.get_mut(class_name).unwrap().functions =
-                            self.classes[base_class].functions.clone();
-                    }
+    // Run an access on the value a place selects
+    fn with_place<R>(
+        &mut self,
+        place: &mut Place,
+        access: impl FnOnce(&Value) -> IplResult<R>,
+    ) -> IplResult<R> {
+        let steps = &place.steps;
+        self.with_target(&mut place.target, |value| access(&*find_item(value, steps)?))
+    }
+
+    // Read the current value of an assignment target
+    fn load(&mut self, place: &mut Place) -> IplResult<Value> {
+        self.with_place(place, |value| Ok(value.clone()))
+    }
+
+    // Write a value to an assignment target, items are changed inside their containers
+    fn store(&mut self, mut place: Place, value: Value) -> IplResult<()> {
+        if !place.steps.is_empty() {
+            let steps = &place.steps;
+            return self.with_target(&mut place.target, |container| {
+                replace_item(container, steps, value)
+            });
+        }
+        match place.target {
+            Target::Variable(name) => self.set_variable_in_scope(&name, value),
+            Target::ClassVariable(class_name, name) => {
+                return self.set_class_variable(&class_name, &name, value);
+            }
+            Target::Attribute(inst, name) => {
+                inst.borrow_mut().variables.insert(name, value);
+            }
+            Target::Temporary(_) => {
+                return EvaluatioError::new(
+                    ErrorKind::Syntax,
+                    "Cannot assign to this expression".to_string(),
+                )
+                .raise();
+            }
+        }
+        Ok(())
+    }
 
-                    self.execute_lines(start_line, end_line, class_name.to_string(), file_path);
+    fn set_class_variable(&mut self, class_name: &str, name: &str, value: Value) -> IplResult<()> {
+        match self.classes.get_mut(class_name) {
+            Some(class) => {
+                class.variables.insert(name.to_string(), value);
+                Ok(())
+            }
+            None => EvaluatioError::new(ErrorKind::Name, "Class not found".to_string()).raise(),
+        }
+    }
 
-                    self.classes
-                        .get_mut(class_name)
-                        .unwrap()
-                        .functions
-                        .extend(self.functions.clone());
+    // Run the body of a function with its arguments as local variables
+    fn call_function(
+        &mut self,
+        function: &Function,
+        class_name: &str,
+        args: Vec<Value>,
+        keywords: Keywords,
+        self_value: &str,
+    ) -> IplResult<Value> {
+        self.call_in_scope(function, class_name, args, keywords, self_value, HashMap::new())
+    }
 
-                    self.functions = funcs;
+    // Run a function with the given variables and its arguments as locals
+    fn call_in_scope(
+        &mut self,
+        function: &Function,
+        class_name: &str,
+        args: Vec<Value>,
+        keywords: Keywords,
+        self_value: &str,
+        mut variables: HashMap<String, Value>,
+    ) -> IplResult<Value> {
+        if state::get_call_depth() >= MAX_CALL_DEPTH {
+            return EvaluatioError::new(
+                ErrorKind::Runtime,
+                "maximum recursion depth exceeded".to_string(),
+            )
+            .raise();
+        }
+        variables.extend(bind_arguments(function, args, keywords)?);
+        self.scopes.push(Scope {
+            environment: Rc::new(Environment::new(variables, function.closure.clone())),
+            ..Scope::default()
+        });
+        let path = std::mem::replace(&mut self.path, function.file.clone());
+        state::push_frame(
+            &function.name,
+            class_name,
+            &self.lib_name,
+            &function.file.to_string_lossy(),
+        );
 
-                    self.indentation_stack.pop();
-                    programm_counter = end_line;
-                }
-                "def" => {
-                    let function_decleration = match line.split_once(' ') {
-                        Some((_, declaration)) => declaration.trim(),
-                        None => "",
-                    };
-                    if function_decleration.is_empty() {
-                        EvaluatioError::new("def requires a function decleration".to_string())
-                            .raise();
-                    }
-                    let function_name = function_decleration.split("(").collect::<Vec<_>>()[0];
-                    let args = function_decleration
-                        .split_once('(') // returns Option<(&str, &str)>
-                        .and_then(|(_, rest)| rest.split_once(')')) // safely get the inside of the parentheses
-                        .map(|(args_str, _)| {
-                            args_str
-                                .split(',')
-                                .map(str::trim)
-                                .filter(|s| !s.is_empty())
-                                .collect::<Vec<_>>()
-                        })
-                        .unwrap_or(Vec::new());
-                    let function_arguments = args
-                        .iter()
-                        .map(|n| Value::Str(n.to_string()))
-                        .collect::<Vec<Value>>();
-                    programm_counter += 1;
-                    let start_line = programm_counter;
-                    while get_indentation(&self.lines[programm_counter]) > indentation {
-                        programm_counter += 1;
-                    }
-                    let function_lines = (start_line..programm_counter)
-                        .map(|n| Value::Number(n as f64))
-                        .collect::<Vec<Value>>();
-                    let mut function_hash_map: HashMap<String, Value> = HashMap::new();
-                    function_hash_map.insert("file".to_string(), Value::Path(Box::new(self.path.clone())));
-                    function_hash_map
-                        .insert("arguments".to_string(), Value::List(function_arguments));
-                    function_hash_map
-                        .insert("function_body".to_string(), Value::List(function_lines));
-                    // println!("Function line {} : {:?}", function_decleration, function_hash_map);
-                    self.functions
-                        .insert(function_name.to_string(), function_hash_map);
-                }
-                _ => {
-                    if line == "End of file" {
-                        break;
-                    }
-                    if line.contains("=") {
-                        if let Some((mut variable_name, expr)) = line.split_once("=") {
-                            let result = self.ev_expr(expr);
-
-                            variable_name = variable_name.trim();
-                            if variable_name.contains("self") {
-                                if self_value.is_empty() {
-                                    EvaluatioError::new("self used outside class".to_string())
-                                        .raise();
-                                } else {
-                                    let var_name = variable_name.split(".").collect::<Vec<_>>()[1];
-                                    if self.classes.contains_key(&self_value) {
-                                        self.classes
-                                            .get_mut(&self_value)
-                                            .unwrap()
-                                            .variables
-                                            .insert(var_name.to_string(), result);
-                                    } else if self.variables.contains_key(&self_value) {
-                                        let inst_var = self.variables.get(&self_value);
-                                        if inst_var.is_none() {
-                                            EvaluatioError::new(
-                                                "Self used outsside of class".to_string(),
-                                            )
-                                            .raise();
-                                        }
-
-                                        let inst_opt = inst_var.unwrap().get_instance();
-                                        if inst_opt.is_none() {
-                                            EvaluatioError::new(
-                                                "Self unwrapping returned a null value".to_string(),
-                                            )
-                                            .raise();
-                                        }
-                                        let mut inst = inst_opt.unwrap();
-
-                                        inst.variables.insert(var_name.to_string(), result);
-                                        self.variables
-                                            .insert(self_value.clone(), Value::Instance(Box::new(inst)));
-
-                                        //let inst = self.variables.get_mut(&self_value).unwrap_or(&mut Value::None).get_instance().unwrap_or(Instance {class: "".to_string(), variables: HashMap::new()}).variables.insert(var_name.to_string(), result);
-                                        //self.variables.insert(self_value.clone(), inst.unwrap_or(Value::None));
-                                    } else {
-                                        EvaluatioError::new(
-                                            "Self reference to class or instance not found"
-                                                .to_string(),
-                                        )
-                                        .raise();
-                                    }
-                                }
-                            } else if variable_name.contains(".") {
-                                let object = variable_name.split(".").collect::<Vec<_>>()[0];
-                                let var_name = variable_name.split(".").collect::<Vec<_>>()[1];
-                                if self.variables.contains_key(object) {
-                                    let mut inst = self
-                                        .variables
-                                        .get_mut(object)
-                                        .unwrap()
-                                        .get_instance()
-                                        .expect("Not an instance");
-                                    inst.variables.insert(var_name.to_string(), result);
-
-                                    self.variables
-                                        .insert(object.to_string(), Value::Instance(Box::new(inst)));
-                                } else if self.classes.contains_key(object) {
-                                    self.classes
-                                        .get_mut(object)
-                                        .unwrap()
-                                        .variables
-                                        .insert(var_name.to_string(), result);
-                                } else {
-                                    EvaluatioError::new("Class not found".to_string()).raise();
-                                }
-                            } else {
-                                self.variables.insert(variable_name.to_string(), result);
-                            }
-                            // println!("Variable name: {}, expr: {}", variable_name, expr);
-                        }
-                    } else {
-                        self.ev_expr(&line);
-                    }
-                    programm_counter += 1;
-                }
+        let flow = self.execute_block(&function.body, self_value);
+
+        state::pop_frame();
+        self.path = path;
+        if let Some(scope) = self.scopes.pop() {
+            scope.environment.release();
+        }
+
+        match flow? {
+            Flow::Return(value) => Ok(value),
+            Flow::Normal => Ok(Value::None),
+            Flow::Break => self.outside_loop_error("break"),
+            Flow::Continue => self.outside_loop_error("continue"),
+        }
+    }
+
+    // Look up a variable, locals of the running function and the functions around it first
+    fn get_variable_in_scope(&self, name: &str) -> Option<Value> {
+        if let Some(scope) = self.scopes.last()
+            && !scope.globals.contains(name)
+            && let Some(value) = scope.environment.get(name)
+        {
+            return Some(value);
+        }
+        self.variables.get(name).cloned()
+    }
+
+    // Check for a variable where get_variable_in_scope looks, without copying its value
+    fn has_variable_in_scope(&self, name: &str) -> bool {
+        if let Some(scope) = self.scopes.last()
+            && !scope.globals.contains(name)
+            && scope.environment.holding(name).is_some()
+        {
+            return true;
+        }
+        self.variables.contains_key(name)
+    }
+
+    // Assign a variable, inside a function it is local unless declared global or nonlocal
+    fn set_variable_in_scope(&mut self, name: &str, value: Value) {
+        let environment = match self.scopes.last() {
+            None => None,
+            Some(scope) if scope.globals.contains(name) => None,
+            Some(scope) if scope.nonlocals.contains(name) => {
+                scope.environment.find_enclosing(name)
+            }
+            Some(scope) => Some(Rc::clone(&scope.environment)),
+        };
+        match environment {
+            Some(environment) => {
+                environment
+                    .variables
+                    .borrow_mut()
+                    .insert(name.to_string(), value);
+            }
+            None => {
+                self.variables.insert(name.to_string(), value);
             }
         }
-        Value::None
     }
 
-    // Evaluate a function by name with given arguments
-    fn ev_func(&mut self, function_name: &str, args: Vec<Value>) -> Value {
-        let function_file: &Value = &self.functions[function_name]["file"].clone();
-        let function_arguments: &Value = &self.functions[function_name]["arguments"].clone(); // Get function arguments
-        let function_lines: &Value = &self.functions[function_name]["function_body"]; // Get function body lines
-
-        // println!("Executing function {} with lines: {:?}", function_name, function_lines);
-        // println!("Function lines content:");
-        // for i in function_lines.iter() {
-        //     println!("  {:?}: '{}'", i, self.lines[i.as_usize()]);
-        // }
-        if args.len() != function_arguments.length() {
-            // Check argument count
-            EvaluatioError::new("Wrong amount of arguments".to_string()).raise();
+    /// Send the output of out() and in() to a writer instead of stdout
+    pub fn set_output(&mut self, output: Rc<RefCell<dyn Write>>) {
+        self.streams.output = output;
+        self.update_library_streams();
+    }
+
+    /// Let in() read lines from a reader instead of stdin
+    pub fn set_input(&mut self, input: Rc<RefCell<dyn BufRead>>) {
+        self.streams.input = Some(input);
+        self.update_library_streams();
+    }
+
+    fn update_library_streams(&mut self) {
+        for evaluator in self.evaluators.values_mut() {
+            evaluator.streams = self.streams.clone();
+            evaluator.update_library_streams();
         }
+    }
 
-        let mut global_vars: HashMap<String, Value> = self.variables.clone(); // Save current variables
+    /// Make a Rust function callable from IPL code, like a built-in function
+    /// It is also available in libraries and replaces a built-in function with the same name
+    pub fn register_function<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(Vec<Value>) -> IplResult<Value> + 'static,
+    {
+        self.register_native_function(
+            name,
+            NativeFunction {
+                arity,
+                function: Rc::new(function),
+            },
+        );
+    }
 
-        for (name, value) in function_arguments.iter().zip(args.into_iter()) {
-            self.variables.insert(name.to_string_value(), value); // Set function arguments in variables
+    fn register_native_function(&mut self, name: &str, native_function: NativeFunction) {
+        // Libraries that are already loaded get the function as well
+        for evaluator in self.evaluators.values_mut() {
+            evaluator.register_native_function(name, native_function.clone());
         }
-        self.indentation_stack.push((
-            "function".to_string(),
-            get_indentation(&self.lines[function_lines[0].as_usize()]),
-        )); // Push function context to indentation stack
-
-        let lines = self.lines.clone();
-        // Execute function lines and get result
-        let result: Value = self
-            .execute_lines(
-                function_lines[0].as_usize(),
-                (function_lines[function_lines.length() - 1].clone() + Value::Number(1.0))
-                    .as_usize(),
-                "".to_string(),
-                &function_file.to_string_value(),
+        self.native_functions.insert(name.to_string(), native_function);
+    }
+
+    /// Call a built-in function, a function defined in IPL or a class constructor by name
+    pub fn call(&mut self, function_name: &str, args: Vec<Value>) -> IplResult<Value> {
+        self.call_with_keywords(function_name, args, vec![])
+    }
+
+    fn call_with_keywords(
+        &mut self,
+        function_name: &str,
+        args: Vec<Value>,
+        keywords: Keywords,
+    ) -> IplResult<Value> {
+        let is_built_in = self.native_functions.contains_key(function_name)
+            || BUILT_IN_FUNCTIONS.contains_key(function_name);
+        if is_built_in && !keywords.is_empty() {
+            return EvaluatioError::new(
+                ErrorKind::Argument,
+                format!("Function '{}' does not take keyword arguments", function_name),
             )
-            .clone();
-        self.lines = lines;
-
-        for name in function_arguments.iter() {
-            if let Some(value) = global_vars.remove(&name.to_string_value()) {
-                self.variables.insert(name.to_string_value(), value);
-            } else {
-                self.variables.remove(&name.to_string_value());
-            }
+            .raise();
         }
-        self.indentation_stack.pop(); // Pop function context from indentation stack
+        if let Some(native_function) = self.native_functions.get(function_name) {
+            native_function.call(function_name, args)
+        } else if BUILT_IN_FUNCTIONS.contains_key(function_name) {
+            call_built_in_function(function_name, args, &self.streams)
+        } else if self.functions.contains_key(function_name) {
+            self.ev_func(function_name, args, keywords)
+        } else if self.classes.contains_key(function_name) {
+            let class_str = ClassStr {
+                class_name: function_name.to_string(),
+                lib_name: "".to_string(),
+            };
+            self.ev_constructor(class_str, args, keywords)
+        } else {
+            EvaluatioError::new(ErrorKind::Name, format!("Unknown function {}", function_name))
+                .raise()
+        }
+    }
 
-        result // Return function result
+    // Evaluate a function by name with given arguments
+    fn ev_func(
+        &mut self,
+        function_name: &str,
+        args: Vec<Value>,
+        keywords: Keywords,
+    ) -> IplResult<Value> {
+        let function = self.functions[function_name].clone();
+        self.call_function(&function, "", args, keywords, "")
     }
 
-    // Evaluate a class method by instance string, method name, and arguments
+    // Evaluate a class method with self set to the instance
     fn ev_class_func(
         &mut self,
-        instance_str: String,
+        instance: Rc<RefCell<Instance>>,
         function_name: &str,
         args: Vec<Value>,
-        instance_opt: Option<Instance>,
-        class_opt: Option<Class>,
-        static_func: bool,
-    ) -> Value {
-        // println!("ev_class_func called with instance: {}, function: {}, args: {:?}, instance_opt:{:?}", instance_str, function_name, args, instance_opt);
-        let mut instance: Instance = Instance {
-            class: ClassStr {
-                class_name: "".to_string(),
-                lib_name: "".to_string(),
-            },
-            variables: HashMap::new(),
+        keywords: Keywords,
+    ) -> IplResult<Value> {
+        let class_str = instance.borrow().class.clone();
+        if self.ipl_libraries.contains_key(&class_str.lib_name) {
+            return self.in_library(&class_str.lib_name, |evaluator| {
+                evaluator.ev_class_func(instance, function_name, args, keywords)
+            });
+        }
+        let function = self.get_class_function(&class_str.class_name, function_name)?;
+
+        let variables = HashMap::from([("self".to_string(), Value::Instance(instance))]);
+        let class_name = &class_str.class_name;
+        self.call_in_scope(&function, class_name, args, keywords, "self", variables)
+    }
+
+    // Evaluate a class method called on the class itself
+    fn ev_static_class_func(
+        &mut self,
+        class_str: ClassStr,
+        function_name: &str,
+        args: Vec<Value>,
+        keywords: Keywords,
+    ) -> IplResult<Value> {
+        if self.ipl_libraries.contains_key(&class_str.lib_name) {
+            let lib_name = class_str.lib_name.clone();
+            return self.in_library(&lib_name, |evaluator| {
+                evaluator.ev_static_class_func(class_str, function_name, args, keywords)
+            });
+        }
+        let function = self.get_class_function(&class_str.class_name, function_name)?;
+        let class_name = &class_str.class_name;
+        self.call_function(&function, class_name, args, keywords, class_name)
+    }
+
+    // Create an instance of a class and run its constructor, if it has one
+    fn ev_constructor(
+        &mut self,
+        class_str: ClassStr,
+        args: Vec<Value>,
+        keywords: Keywords,
+    ) -> IplResult<Value> {
+        if self.ipl_libraries.contains_key(&class_str.lib_name) {
+            let lib_name = class_str.lib_name.clone();
+            return self.in_library(&lib_name, |evaluator| {
+                evaluator.ev_constructor(class_str, args, keywords)
+            });
+        }
+        let Some(class) = self.classes.get(&class_str.class_name) else {
+            return EvaluatioError::new(
+                ErrorKind::Name,
+                format!("Class {} not found", class_str.class_name),
+            )
+            .raise();
         };
-        if !static_func {
-            if instance_opt.is_some() { // Dont use let Some(instance) = instance_opt
-                instance = instance_opt.unwrap();
-                self.variables
-                    .insert(instance_str.clone(), Value::Instance(Box::new(instance.clone())));
-            } else if !self.variables.contains_key(&instance_str) {
-                EvaluatioError::new("Instance not found".to_string()).raise();
-            } else {
-                instance = self
-                    .variables
-                    .get(&instance_str)
-                    .expect("Instance not found")
-                    .get_instance()
-                    .expect("Not an instance");
+        let has_constructor = class.functions.contains_key(&class_str.class_name);
+        let instance = Rc::new(RefCell::new(Instance {
+            class: class_str.clone(),
+            variables: class.variables.clone(),
+        }));
+        if has_constructor {
+            self.ev_class_func(Rc::clone(&instance), &class_str.class_name, args, keywords)?;
+        } else if !args.is_empty() || !keywords.is_empty() {
+            return EvaluatioError::new(
+                ErrorKind::Argument,
+                format!(
+                    "Class {} has no constructor, but got {} arguments",
+                    class_str.class_name,
+                    args.len() + keywords.len()
+                ),
+            )
+            .raise();
+        }
+        Ok(Value::Instance(instance))
+    }
+
+    fn ev_lib_func(
+        &mut self,
+        lib_name: &str,
+        function_name: &str,
+        args: Vec<Value>,
+        keywords: Keywords,
+    ) -> IplResult<Value> {
+        self.in_library(lib_name, |evaluator| {
+            if !evaluator.functions.contains_key(function_name) {
+                return EvaluatioError::new(
+                    ErrorKind::Name,
+                    "Function was not found in library".to_string(),
+                )
+                .raise();
+            }
+            evaluator.ev_func(function_name, args, keywords)
+        })
+    }
+
+    // Run code in the evaluator of a library. This evaluator becomes its parent while it runs,
+    // so functions passed into the library can be called back here
+    fn in_library<T>(
+        &mut self,
+        lib_name: &str,
+        run: impl FnOnce(&mut Evaluator) -> IplResult<T>,
+    ) -> IplResult<T> {
+        let Some(mut evaluator) = self.evaluators.remove(lib_name) else {
+            return EvaluatioError::new(
+                ErrorKind::Import,
+                "Evaluator for library not found".to_string(),
+            )
+            .raise();
+        };
+        evaluator.parent = Some(Box::new(std::mem::take(self)));
+        let result = run(&mut evaluator);
+        *self = *evaluator.parent.take().unwrap();
+        self.evaluators.insert(lib_name.to_string(), evaluator);
+        result
+    }
+
+    // Run code in the evaluator that called into this library, or here if there is none
+    fn in_parent<T>(&mut self, run: impl FnOnce(&mut Evaluator) -> IplResult<T>) -> IplResult<T> {
+        let Some(mut parent) = self.parent.take() else {
+            return run(self);
+        };
+        let lib_name = self.lib_name.clone();
+        parent.evaluators.insert(lib_name.clone(), std::mem::take(self));
+        let result = run(&mut parent);
+        *self = parent.evaluators.remove(&lib_name).unwrap();
+        self.parent = Some(parent);
+        result
+    }
+
+    // Check if a library is loaded here or by one of the libraries loaded here
+    fn has_library(&self, lib_name: &str) -> bool {
+        self.evaluators.contains_key(lib_name)
+            || self.evaluators.values().any(|evaluator| evaluator.has_library(lib_name))
+    }
+
+    // Call a function value in the evaluator it was defined in, so it sees its own globals
+    fn call_function_value(
+        &mut self,
+        function: Function,
+        args: Vec<Value>,
+        keywords: Keywords,
+    ) -> IplResult<Value> {
+        if function.lib_name != self.lib_name {
+            let child = self
+                .evaluators
+                .iter()
+                .find(|(name, evaluator)| {
+                    **name == function.lib_name || evaluator.has_library(&function.lib_name)
+                })
+                .map(|(name, _)| name.clone());
+            if let Some(child) = child {
+                return self.in_library(&child, |evaluator| {
+                    evaluator.call_function_value(function, args, keywords)
+                });
+            }
+            if self.parent.is_some() {
+                return self.in_parent(|evaluator| {
+                    evaluator.call_function_value(function, args, keywords)
+                });
             }
         }
-        let mut class = Class {
-            functions: HashMap::new(),
-            variables: HashMap::new(),
+        // Functions defined in a method can use its self
+        let self_value = match &function.closure {
+            Some(closure) if closure.get("self").is_some() => "self",
+            _ => "",
         };
-        if class_opt.is_some() { // Dont use let Some(class) = class_opt
-            class = class_opt.unwrap();
-            self.classes
-                .insert(instance.class.class_name.clone(), class.clone());
-        } else if !self.classes.contains_key(&instance.class.class_name) {
-            EvaluatioError::new("Class not in classes".to_string()).raise();
-        } else {
-            class = self
-                .classes
-                .get(&instance.class.class_name)
-                .expect("Class not found")
-                .clone();
+        self.call_function(&function, "", args, keywords, self_value)
+    }
+
+    fn get_class_function(&self, class_name: &str, function_name: &str) -> IplResult<Function> {
+        let Some(class) = self.classes.get(class_name) else {
+            return EvaluatioError::new(ErrorKind::Name, format!("Class {} not found", class_name))
+                .raise();
+        };
+        match class.functions.get(function_name) {
+            Some(function) => Ok(function.clone()),
+            None => EvaluatioError::new(
+                ErrorKind::Name,
+                format!("Class {} has no function {}", class_name, function_name),
+            )
+            .raise(),
         }
-        if !class.functions.contains_key(function_name) {
-            return Value::None;
+    }
+
+    fn get_class(&self, class_str: &ClassStr) -> IplResult<&Class> {
+        let class = match self.ipl_libraries.get(&class_str.lib_name) {
+            Some(lib) => lib.classes.get(&class_str.class_name),
+            None => self.classes.get(&class_str.class_name),
+        };
+        match class {
+            Some(class) => Ok(class),
+            None => EvaluatioError::new(
+                ErrorKind::Type,
+                "Left side of '.' is not a class".to_string(),
+            )
+            .raise(),
         }
+    }
 
-        let function_file: &Value = &class.functions[function_name]["file"];
-        let function_arguments: &Value = &class.functions[function_name]["arguments"];
-        let function_lines: &Value = &class.functions[function_name]["function_body"];
-
-        // println!("Executing class function {} with lines: {:?}", function_name, function_lines);
-        // println!("Function lines content:");
-        // for i in function_lines.iter() {
-        //     println!("  {:?}: '{}'", i, self.lines[i.as_usize()]);
-        // }
-        if args.len() != function_arguments.length() {
-            EvaluatioError::new("Wrong amount of arguments".to_string()).raise();
+    fn ev_call(&mut self, callee: &Expr, args: Vec<Value>, keywords: Keywords) -> IplResult<Value> {
+        // println!("Function {:?} called with arguments: {:?}", callee, args);
+        match &callee.kind {
+            // A variable can hold a function, like a callback passed as an argument
+            ExprKind::Variable(function_name) => match self.get_variable_in_scope(function_name) {
+                Some(value) => self.call_value(value, args, keywords),
+                None => self.call_with_keywords(function_name, args, keywords),
+            },
+            ExprKind::Attribute(object, function_name) => match self.ev_expr(object)? {
+                Value::Instance(inst) => {
+                    let field = inst.borrow().variables.get(function_name).cloned();
+                    match field {
+                        Some(value) => self.call_value(value, args, keywords),
+                        None => self.ev_class_func(inst, function_name, args, keywords),
+                    }
+                }
+                Value::IPL_Library(lib) => {
+                    if lib.functions.contains_key(function_name) {
+                        self.ev_lib_func(&lib.lib_name, function_name, args, keywords)
+                    } else if lib.classes.contains_key(function_name) {
+                        let class_str = ClassStr {
+                            class_name: function_name.to_string(),
+                            lib_name: lib.lib_name,
+                        };
+                        self.ev_constructor(class_str, args, keywords)
+                    } else {
+                        EvaluatioError::new(
+                            ErrorKind::Name,
+                            "No valid attribute on library".to_string(),
+                        )
+                        .raise()
+                    }
+                }
+                Value::ClassStr(class_str) => {
+                    self.ev_static_class_func(*class_str, function_name, args, keywords)
+                }
+                _ => EvaluatioError::new(
+                    ErrorKind::Type,
+                    "Left side of '.' is not an instance".to_string(),
+                )
+                .raise(),
+            },
+            _ => {
+                let callee = self.ev_expr(callee)?;
+                self.call_value(callee, args, keywords)
+            }
         }
-        let global_vars = self.variables.clone();
+    }
 
-        // println!("function_arguments: {:?} and args: {:?}", function_arguments, args);
-        for (name, value) in function_arguments.iter().zip(args.iter()) {
-            // println!("Setting variable {} to {:?}", name.to_string_value(), value);
-            self.variables.insert(name.to_string_value(), value.clone());
+    // Call a function value, or create an instance when the value is a class
+    fn call_value(
+        &mut self,
+        callee: Value,
+        args: Vec<Value>,
+        keywords: Keywords,
+    ) -> IplResult<Value> {
+        match callee {
+            Value::Function(callable) => match *callable {
+                Callable::Function(function) => self.call_function_value(function, args, keywords),
+                Callable::Method(instance, name) => {
+                    self.ev_class_func(instance, &name, args, keywords)
+                }
+                Callable::ClassFunction(class_str, name) => {
+                    self.ev_static_class_func(class_str, &name, args, keywords)
+                }
+                // A library calling one of its own functions it got back as a value
+                Callable::LibraryFunction(lib_name, name) if lib_name == self.lib_name => {
+                    self.ev_func(&name, args, keywords)
+                }
+                Callable::LibraryFunction(lib_name, name) => {
+                    self.ev_lib_func(&lib_name, &name, args, keywords)
+                }
+                Callable::BuiltIn(name) => self.call_with_keywords(&name, args, keywords),
+            },
+            Value::ClassStr(class_str) => self.ev_constructor(*class_str, args, keywords),
+            other => EvaluatioError::new(
+                ErrorKind::Type,
+                format!("Cannot call {}", other.type_name_with_article()),
+            )
+            .raise(),
         }
-        // println!("self.variables before function execution: {:#?}", self.variables);
-        // println!("self.classes before function execution: {:#?}", self.classes);
-        self.indentation_stack.push((
-            "function".to_string(),
-            get_indentation(&self.lines[function_lines[0].as_usize()]),
-        ));
-
-        let lines = self.lines.clone();
-
-        let result = self.execute_lines(
-            function_lines[0].as_usize(),
-            (function_lines[function_lines.length() - 1].clone() + Value::Number(1.0)).as_usize(),
-            instance_str.clone(),
-            &function_file.to_string_value(),
-        );
-        // println!("self.variables after function execution: {:#?}", self.variables);
-        self.indentation_stack.pop();
-
-        self.lines = lines;
-
-        for name in function_arguments.iter() {
-            if global_vars.contains_key(&name.to_string_value()) {
-                self.variables.insert(
-                    name.to_string_value(),
-                    global_vars
-                        .get(&name.to_string_value())
-                        .expect("The if for function argument ressetting failed")
-                        .clone(),
-                );
-            } else {
-                self.variables.remove(&name.to_string_value());
+    }
+
+    // The value of a function or class name, looked up in the same order as in call
+    fn function_value(&self, name: &str) -> Option<Value> {
+        let callable = if self.native_functions.contains_key(name)
+            || BUILT_IN_FUNCTIONS.contains_key(name)
+        {
+            Callable::BuiltIn(name.to_string())
+        } else if let Some(function) = self.functions.get(name) {
+            Callable::Function(function.clone())
+        } else if self.classes.contains_key(name) {
+            return Some(Value::ClassStr(Box::new(ClassStr {
+                class_name: name.to_string(),
+                lib_name: "".to_string(),
+            })));
+        } else {
+            return None;
+        };
+        Some(Value::Function(Box::new(callable)))
+    }
+
+    fn ev_attribute(&mut self, object: &Expr, attribute: &str) -> IplResult<Value> {
+        let object = self.ev_expr(object)?;
+        self.attribute_of(object, attribute)
+    }
+
+    fn attribute_of(&mut self, object: Value, attribute: &str) -> IplResult<Value> {
+        match object {
+            Value::Instance(inst) => {
+                if let Some(value) = inst.borrow().variables.get(attribute) {
+                    return Ok(value.clone());
+                }
+                let class_str = inst.borrow().class.clone();
+                if self.get_class(&class_str)?.functions.contains_key(attribute) {
+                    // A method that is not called right away remembers its instance
+                    let method = Callable::Method(inst, attribute.to_string());
+                    return Ok(Value::Function(Box::new(method)));
+                }
+                EvaluatioError::new(
+                    ErrorKind::Name,
+                    format!("Instance has no attribute {}", attribute),
+                )
+                .raise()
+            }
+            Value::IPL_Library(lib) => {
+                if let Some(value) = lib.variables.get(attribute) {
+                    Ok(value.clone())
+                } else if lib.functions.contains_key(attribute) {
+                    let function = Callable::LibraryFunction(lib.lib_name, attribute.to_string());
+                    Ok(Value::Function(Box::new(function)))
+                } else if lib.classes.contains_key(attribute) {
+                    Ok(Value::ClassStr(Box::new(ClassStr {
+                        class_name: attribute.to_string(),
+                        lib_name: lib.lib_name,
+                    })))
+                } else {
+                    EvaluatioError::new(ErrorKind::Name, "No valid attribute on library".to_string())
+                        .raise()
+                }
             }
+            Value::ClassStr(class_str) => {
+                let class = self.get_class(&class_str)?;
+                if let Some(value) = class.variables.get(attribute) {
+                    Ok(value.clone())
+                } else if class.functions.contains_key(attribute) {
+                    let function = Callable::ClassFunction(*class_str, attribute.to_string());
+                    Ok(Value::Function(Box::new(function)))
+                } else {
+                    EvaluatioError::new(
+                        ErrorKind::Name,
+                        format!("Class has no attribute {}", attribute),
+                    )
+                    .raise()
+                }
+            }
+            _ => EvaluatioError::new(
+                ErrorKind::Type,
+                "Left side of '.' is not an instance".to_string(),
+            )
+            .raise(),
         }
+    }
 
-        result
+    fn ev_index(
+        &mut self,
+        target: &Expr,
+        start: &Option<Box<Expr>>,
+        end: &Option<Box<Expr>>,
+        step: &Option<Box<Expr>>,
+        slice: bool,
+    ) -> IplResult<Value> {
+        let mut place = self.index_place(target, start, end, step, slice)?;
+        self.load(&mut place)
     }
 
-    fn ev_lib_func(&mut self, lib_name: String, function_name: &str, args: Vec<Value>) -> Value {
-        // println!("Self.ipl_libraries: {:#?}", self.ipl_libraries);
-        // println!("Ev_lib_func called for library: {}, and function name: {}", lib_name, function_name);
-        // let lib_path = get_library_entry_path(&lib_name).to_str().unwrap().to_string();
-        // println!("Lib path: {}", lib_path);
-        if self.ipl_libraries.contains_key(&lib_name) {
-            let lib_functions = self.ipl_libraries[&lib_name].functions.clone();
-            if !lib_functions.contains_key(function_name) {
-                EvaluatioError::new("Function was not found in library".to_string()).raise();
-                return Value::None;
+    // Where the value of an expression is stored, so items of it can be read without copying it
+    fn read_place(&mut self, expr: &Expr) -> IplResult<Place> {
+        match &expr.kind {
+            ExprKind::Variable(name) if self.has_variable_in_scope(name) => {
+                Ok(Place::new(Target::Variable(name.to_string())))
             }
-            let file = lib_functions[function_name]["file"].clone();
-            if file.to_string_value() != self.path.to_str().unwrap() {
-                if let Some(ev) = self.evaluators.get_mut(&file.to_string_value()) {
-                    // println!("Calling ev_lib_func on: {:?}", file);
-                    ev.ev_lib_func(lib_name, function_name, args)
-                } else {
-                    EvaluatioError::new("Evaluator for file not found".to_string()).raise();
-                    Value::None
+            ExprKind::Attribute(object, attribute) => {
+                let object = self.ev_expr(object)?;
+                if let Value::Instance(inst) = &object
+                    && inst.borrow().variables.contains_key(attribute)
+                {
+                    return Ok(Place::new(Target::Attribute(Rc::clone(inst), attribute.to_string())));
                 }
-            } else {
-                EvaluatioError::new("This shouldnt happen, tell the dev(s) that the ev_lib_func found the lib in self.ipl_libraries, although its already in the lib file".to_string()).raise();
-                Value::None
+                let value = self.attribute_of(object, attribute).map_err(point_at(expr))?;
+                Ok(Place::new(Target::Temporary(value)))
             }
-        } else {
-            let result = self.ev_func(function_name, args);
-            // println!("Result for ev_lib_func: {:?}", result);
-            result
+            ExprKind::Index {
+                target,
+                start,
+                end,
+                step,
+                slice,
+            } => self.index_place(target, start, end, step, *slice).map_err(point_at(expr)),
+            _ => Ok(Place::new(Target::Temporary(self.ev_expr(expr)?))),
         }
     }
 
-    fn ev_lib_class_func(
+    // Select the items of a container for reading
+    fn index_place(
         &mut self,
-        lib_name: String,
-        class_name: String,
-        instance_str: String,
-        function_name: &str,
-        args: Vec<Value>,
-        instance_opt: Option<Instance>,
-        class_opt: Option<Class>,
-        static_func: bool,
-    ) -> Value {
-        if self.ipl_libraries.contains_key(&lib_name) {
-            let class_functions = self.ipl_libraries[&lib_name].classes[&class_name]
-                .functions
-                .clone();
-            if !class_functions.contains_key(function_name) {
-                EvaluatioError::new("Class function was not found in library".to_string()).raise();
-                return Value::None;
+        target: &Expr,
+        start: &Option<Box<Expr>>,
+        end: &Option<Box<Expr>>,
+        step: &Option<Box<Expr>>,
+        slice: bool,
+    ) -> IplResult<Place> {
+        let mut container = self.read_place(target)?;
+        match self.with_place(&mut container, |value| Ok(Shape::of(value)))? {
+            Shape::Map => {
+                let key = self.ev_map_key(start, slice)?;
+                container.steps.push(Step::Key(key));
+                return Ok(container);
             }
-            let file = class_functions[function_name]["file"].clone();
-            if file.to_string_value() != self.path.to_str().unwrap() {
-                if let Some(ev) = self.evaluators.get_mut(&file.to_string_value()) {
-                    // println!("Calling ev_lib_func on: {:?}", file);
-                    ev.ev_lib_class_func(
-                        lib_name,
-                        class_name,
-                        instance_str,
-                        function_name,
-                        args,
-                        instance_opt,
-                        class_opt,
-                        static_func,
-                    )
-                } else {
-                    EvaluatioError::new("Evaluator for file not found".to_string()).raise();
-                    Value::None
+            Shape::List(length) => {
+                let selection = self.ev_selection(length, start, end, step, slice)?;
+                container.steps.push(Step::Items(selection));
+                return Ok(container);
+            }
+            Shape::Other => {}
+        }
+        let value = match self.load(&mut container)? {
+            // Strings are indexed by characters, not bytes
+            Value::Str(string) => {
+                let chars: Vec<char> = string.chars().collect();
+                match self.ev_selection(chars.len(), start, end, step, slice)? {
+                    Selection::Index(index) => Value::Str(chars[index].to_string()),
+                    Selection::Slice(start, stop, step) => Value::Str(
+                        slice_positions(start, stop, step)
+                            .map(|index| chars[index])
+                            .collect(),
+                    ),
                 }
-            } else {
-                EvaluatioError::new("This shouldnt happen, tell the dev(s) that the ev_lib_func found the lib in self.ipl_libraries, although its already in the lib file".to_string()).raise();
-                Value::None
             }
+            other => {
+                return EvaluatioError::new(
+                    ErrorKind::Type,
+                    format!("Cannot index {}", other.type_name_with_article()),
+                )
+                .raise();
+            }
+        };
+        Ok(Place::new(Target::Temporary(value)))
+    }
+
+    // Evaluate the key inside the [] of a map
+    fn ev_map_key(&mut self, key: &Option<Box<Expr>>, slice: bool) -> IplResult<Value> {
+        match (key, slice) {
+            (Some(key), false) => self.ev_expr(key),
+            _ => EvaluatioError::new(ErrorKind::Type, "Maps cannot be sliced".to_string())
+                .raise(),
+        }
+    }
+
+    // Evaluate the inside of [] for a sequence of the given length
+    fn ev_selection(
+        &mut self,
+        length: usize,
+        start: &Option<Box<Expr>>,
+        end: &Option<Box<Expr>>,
+        step: &Option<Box<Expr>>,
+        slice: bool,
+    ) -> IplResult<Selection> {
+        let length = length as i64;
+        if !slice {
+            let Some(start) = start else {
+                return EvaluatioError::new(ErrorKind::Syntax, "Expected an index".to_string())
+                    .raise();
+            };
+            let index = self.ev_integer(start)?;
+            // Negative indices count from the end
+            let position = if index < 0 { index + length } else { index };
+            if !(0..length).contains(&position) {
+                return EvaluatioError::new(
+                    ErrorKind::Index,
+                    format!("Index {} is out of bounds for length {}", index, length),
+                )
+                .raise();
+            }
+            return Ok(Selection::Index(position as usize));
+        }
+
+        let step = match step {
+            Some(step) => self.ev_integer(step)?,
+            None => 1,
+        };
+        if step == 0 {
+            return EvaluatioError::new(ErrorKind::Index, "Slice step cannot be zero".to_string())
+                .raise();
+        }
+        // Like in Python, the ends of a slice are clamped to the sequence
+        let (lower, upper) = if step > 0 { (0, length) } else { (-1, length - 1) };
+        let mut clamp = |bound: &Option<Box<Expr>>, default: i64| -> IplResult<i64> {
+            Ok(match bound {
+                None => default,
+                Some(bound) => match self.ev_integer(bound)? {
+                    index if index < 0 => (index + length).max(lower),
+                    index => index.min(upper),
+                },
+            })
+        };
+        let (start, stop) = if step > 0 {
+            (clamp(start, lower)?, clamp(end, upper)?)
         } else {
-            let result = self.ev_class_func(
-                instance_str,
-                function_name,
-                args,
-                instance_opt,
-                class_opt,
-                static_func,
-            );
-            // println!("Result for ev_lib_func: {:?}", result);
-            result
+            (clamp(start, upper)?, clamp(end, lower)?)
+        };
+        Ok(Selection::Slice(start, stop, step))
+    }
+
+    // Evaluate an index, which has to be an int
+    fn ev_integer(&mut self, expr: &Expr) -> IplResult<i64> {
+        match self.ev_expr(expr)? {
+            Value::Int(number) => Ok(number),
+            other => EvaluatioError::new(
+                ErrorKind::Type,
+                format!("Indices must be integers, not {}", other.type_name()),
+            )
+            .at(expr.start, expr.end)
+            .raise(),
         }
     }
 
-    fn ev_expr(&mut self, expr: &str) -> Value {
-        let tokens = self.tokenizer.tokenize(
-            expr,
-            &self.variables,
-            &self.functions,
-            &self.classes,
-            &self.ipl_libraries,
-        );
+    fn ev_unary_op(&self, operator: &str, operand: Value) -> IplResult<Value> {
+        match (operator, operand) {
+            ("-", Value::Int(number)) => match number.checked_neg() {
+                Some(negated) => Ok(Value::Int(negated)),
+                None => overflow_error("unary -"),
+            },
+            ("-", Value::Number(number)) => Ok(Value::Number(-number)),
+            ("+", number @ (Value::Int(_) | Value::Number(_))) => Ok(number),
+            ("not", operand) => Ok(Value::Bool(!operand.as_bool())),
+            (operator, operand) => EvaluatioError::new(
+                ErrorKind::Type,
+                format!("Unsupported type for unary {}: {}", operator, operand.type_name()),
+            )
+            .raise(),
+        }
+    }
 
-        // println!("tokens: {:?}", tokens);
-
-        let mut stack: Vec<Value> = vec![];
-        let mut i = 0;
-        while i < tokens.len() {
-            let token = tokens.get(i).expect("Empty token");
-            let token_str = token.to_string_value();
-            // println!("token: {} , stack: {:?}", token_str, stack);
-            if token_str.trim_matches('.').parse::<f64>().is_ok() {
-                stack.push(Value::Number(token_str.parse::<f64>().unwrap()));
-            } else if (token_str.starts_with('"') && token_str.ends_with('"'))
-                || (token_str.starts_with("'") && token_str.ends_with("'"))
-            {
-                stack.push(Value::Str(token_str[1..token_str.len() - 1].to_string()));
-            } else if matches!(token, Value::List(_)) {
-                stack.push(token.clone());
-            } else if self.variables.contains_key(&token_str) {
-                stack.push(self.variables[&token_str].clone());
-            } else if self.ipl_libraries.contains_key(&token_str) {
-                stack.push(Value::IPL_Library(Box::new(self.ipl_libraries[&token_str].clone())));
-            } else if self.functions.contains_key(&token_str)
-                || BUILT_IN_FUNCTIONS.contains_key(&token_str as &str)
-                || self.classes.contains_key(&token_str)
-            {
-                let function_name = &token_str;
-                let mut args: Vec<Value> = vec![];
-                // println!("Function call detected: {}", function_name);
-                // println!("Functions: {:?}", self.functions);
-                let function_args = tokens.get(i + 1);
-                for arg in function_args.unwrap_or(&Value::None).iter() {
-                    if let Value::Str(s) = arg {
-                        let evaluated_arg = self.ev_expr(s);
-                        args.push(evaluated_arg);
-                    } else {
-                        args.push(arg.clone());
-                    }
-                }
-                if !matches!(function_args.unwrap(), Value::List(_)) {
-                    stack.push(Value::Str(token_str));
-                    i += 1;
-                    continue;
+    fn ev_binary_op(&self, lhs: Value, operator: &str, rhs: Value) -> IplResult<Value> {
+        let type_names = format!("{} and {}", lhs.type_name(), rhs.type_name());
+        let both_ints = matches!((&lhs, &rhs), (Value::Int(_), Value::Int(_)));
+        let result = match operator {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" | "//" | "%" if lhs.is_number() && rhs == Value::Number(0.0) => {
+                return division_by_zero_error();
+            }
+            "**" if is_zero_to_negative_power(&lhs, &rhs) => return division_by_zero_error(),
+            "/" => lhs / rhs,
+            "//" => lhs.floor_div(rhs),
+            "%" => lhs % rhs,
+            "**" => lhs.pow(rhs),
+            "==" => Value::Bool(lhs == rhs),
+            "!=" => Value::Bool(lhs != rhs),
+            "<" => Value::Bool(lhs < rhs),
+            "<=" => Value::Bool(lhs <= rhs),
+            ">" => Value::Bool(lhs > rhs),
+            ">=" => Value::Bool(lhs >= rhs),
+            "in" => match rhs.contains(&lhs) {
+                Some(found) => Value::Bool(found),
+                None => Value::None,
+            },
+            "and" => Value::Bool(lhs.as_bool() && rhs.as_bool()),
+            "or" => Value::Bool(lhs.as_bool() || rhs.as_bool()),
+            _ => {
+                return EvaluatioError::new(
+                    ErrorKind::Syntax,
+                    format!("Unknown operator {}", operator),
+                )
+                .raise();
+            }
+        };
+        // The arithmetic operators and in give None for types they dont support,
+        // arithmetic on two ints only when the result is too large for an int
+        if result.is_none_value() && both_ints && operator != "in" {
+            return overflow_error(operator);
+        }
+        if result.is_none_value() {
+            return EvaluatioError::new(
+                ErrorKind::Type,
+                format!("Unsupported types for {}: {}", operator, type_names),
+            )
+            .raise();
+        }
+        Ok(result)
+    }
+
+    // Evaluate an expression, pointing errors raised by it at its columns
+    fn ev_expr(&mut self, expr: &Expr) -> IplResult<Value> {
+        self.ev_expr_kind(&expr.kind).map_err(point_at(expr))
+    }
+
+    fn ev_expr_kind(&mut self, kind: &ExprKind) -> IplResult<Value> {
+        match kind {
+            ExprKind::Int(number) => Ok(Value::Int(*number)),
+            ExprKind::Number(number) => Ok(Value::Number(*number)),
+            ExprKind::Str(string) => Ok(Value::Str(string.to_string())),
+            ExprKind::List(elements) => {
+                let mut list = vec![];
+                for element in elements {
+                    list.push(self.ev_expr(element)?);
                 }
-                if args.len() == 1 && args[0].to_string_value() == Value::None.to_string_value() {
-                    args = vec![];
+                Ok(Value::List(list))
+            }
+            ExprKind::Map(entries) => {
+                let mut map = Value::Map(vec![]);
+                for (key, value) in entries {
+                    let key = self.ev_expr(key)?;
+                    check_map_key(&key)?;
+                    let value = self.ev_expr(value)?;
+                    map.set_entry(key, value);
                 }
-                // println!("Function {} called with arguments: {:?}", function_name, args);
-                let result = if BUILT_IN_FUNCTIONS.contains_key(function_name as &str) {
-                    call_built_in_function(function_name, args)
-                } else if self.functions.contains_key(function_name) {
-                    self.ev_func(function_name, args)
-                } else if self.classes.contains_key(function_name) {
-                    let instance = Instance {
-                        class: ClassStr {
-                            class_name: function_name.to_string(),
-                            lib_name: "".to_string(),
-                        },
-                        variables: self.classes[function_name].variables.clone(),
-                    };
-                    self.ev_class_func(
-                        "__DO_NOT_USE_THIS_VARIABLE_INTERNAL_ONLY__"
-                            .trim()
-                            .to_string(),
-                        function_name,
-                        args,
-                        Some(instance),
-                        None,
-                        false,
-                    );
-
-                    Value::Instance(Box::new(
-                        self.variables
-                            .get("__DO_NOT_USE_THIS_VARIABLE_INTERNAL_ONLY__")
-                            .expect("Instance not found")
-                            .get_instance()
-                            .expect("Not an instance"),
-                    ))
+                Ok(map)
+            }
+            ExprKind::Variable(name) => {
+                if let Some(value) = self.get_variable_in_scope(name) {
+                    Ok(value)
+                } else if let Some(lib) = self.ipl_libraries.get(name) {
+                    Ok(Value::IPL_Library(Box::new(lib.clone())))
+                } else if let Some(function) = self.function_value(name) {
+                    Ok(function)
                 } else {
-                    Value::None
+                    EvaluatioError::new(ErrorKind::Name, format!("Unknown variable {}", name))
+                        .raise()
+                }
+            }
+            ExprKind::Attribute(object, attribute) => self.ev_attribute(object, attribute),
+            ExprKind::Index {
+                target,
+                start,
+                end,
+                step,
+                slice,
+            } => self.ev_index(target, start, end, step, *slice),
+            ExprKind::Call(callee, args, keyword_args) => {
+                let mut arguments = vec![];
+                for arg in args {
+                    arguments.push(self.ev_expr(arg)?);
+                }
+                let mut keywords = vec![];
+                for (name, arg) in keyword_args {
+                    keywords.push((name.to_string(), self.ev_expr(arg)?));
+                }
+                self.ev_call(callee, arguments, keywords)
+            }
+            ExprKind::BinaryOp(lhs, operator, rhs) => {
+                let lhs = self.ev_expr(lhs)?;
+                // The right side of and/or is only evaluated when it decides the result
+                match operator.as_str() {
+                    "and" if !lhs.as_bool() => return Ok(Value::Bool(false)),
+                    "or" if lhs.as_bool() => return Ok(Value::Bool(true)),
+                    _ => {}
+                }
+                let rhs = self.ev_expr(rhs)?;
+                self.ev_binary_op(lhs, operator, rhs)
+            }
+            ExprKind::UnaryOp(operator, operand) => {
+                let operand = self.ev_expr(operand)?;
+                self.ev_unary_op(operator, operand)
+            }
+            ExprKind::Lambda(arguments, body) => {
+                let body = Stmt {
+                    kind: StmtKind::Return(Some(*body.clone())),
+                    line_number: state::get_line_number(),
+                    line_content: state::get_line_content(),
                 };
-                stack.push(result);
-                i += 1; // Skip the next token which is the argument list
-            } else if token.to_string_value() == "." {
-                let base = stack.pop().expect("No instance before .");
-                let attribute = tokens.get(i + 1).expect("No attribute after .");
-                match base {
-                    Value::Instance(inst) => {
-                        if inst.variables.contains_key(&attribute.to_string_value()) {
-                            stack.push(inst.variables[&attribute.to_string_value()].clone());
-                        } else if self.classes.contains_key(&inst.class.class_name) {
-                            let class = &self.classes[&inst.class.class_name];
-                            if class.functions.contains_key(&attribute.to_string_value()) {
-                                let function_name = &attribute.to_string_value();
-                                let mut args: Vec<Value> = vec![];
-                                let function_args = tokens.get(i + 2);
-                                for arg in function_args.unwrap_or(&Value::None).iter() {
-                                    if let Value::Str(s) = arg {
-                                        let evaluated_arg = self.ev_expr(s);
-                                        args.push(evaluated_arg);
-                                    } else {
-                                        args.push(arg.clone());
-                                    }
-                                }
-                                if args.len() == 1
-                                    && args[0].to_string_value() == Value::None.to_string_value()
-                                {
-                                    args = vec![];
-                                }
-                                // println!("Class function {} called with arguments: {:?}", function_name, args);
-                                let result = self.ev_class_func(
-                                    tokens[i - 1].to_string_value(),
-                                    function_name,
-                                    args,
-                                    None,
-                                    None,
-                                    false,
-                                );
-                                stack.push(result);
-                                i += 1; // Skip the next token which is the argument list
-                            }
-                        } else {
-                            EvaluatioError::new(format!(
-                                "Instance has no attribute {}",
-                                attribute.to_string_value()
-                            ))
-                            .raise();
-                        }
-                    }
-                    Value::IPL_Library(lib) => {
-                        let attribute_str = attribute.to_string_value();
-                        if lib.variables.contains_key(&attribute_str) {
-                            stack.push(lib.variables[&attribute_str].clone());
-                        } else if lib.classes.contains_key(&attribute_str) {
-                            stack.push(Value::ClassStr(Box::new(ClassStr {
-                                class_name: attribute_str,
-                                lib_name: lib.lib_name,
-                            })));
-                        } else if lib.functions.contains_key(&attribute_str) {
-                            let function_name = &attribute.to_string_value();
-                            let mut args: Vec<Value> = vec![];
-                            let function_args = tokens.get(i + 2);
-                            for arg in function_args.unwrap_or(&Value::None).iter() {
-                                if let Value::Str(s) = arg {
-                                    let evaluated_arg = self.ev_expr(s);
-                                    args.push(evaluated_arg);
-                                } else {
-                                    args.push(arg.clone());
-                                }
-                            }
-                            if args.len() == 1
-                                && args[0].to_string_value() == Value::None.to_string_value()
-                            {
-                                args = vec![];
-                            }
-                            // println!("Library function {} called with arguments: {:?}", function_name, args);
-                            let result = self.ev_lib_func(
-                                tokens[i - 1].to_string_value(), // Lib name
-                                function_name,
-                                args,
-                            );
-                            stack.push(result);
-                            i += 1; // Skip the next token which is the argument list
-                        } else {
-                            EvaluatioError::new("No valid attribute on library".to_string())
-                                .raise();
-                        }
-                    }
-                    Value::Str(class_str) => {
-                        if !self.classes.contains_key(&class_str) {
-                            EvaluatioError::new("Left side of '.' is not a class".to_string())
+                let function = Function {
+                    name: "<lambda>".to_string(),
+                    file: self.path.clone(),
+                    arguments: arguments.clone(),
+                    defaults: HashMap::new(),
+                    body: Rc::new(vec![body]),
+                    closure: self.scopes.last().map(|scope| Rc::clone(&scope.environment)),
+                    lib_name: self.lib_name.clone(),
+                };
+                Ok(Value::Function(Box::new(Callable::Function(function))))
+            }
+            ExprKind::FormatString(parts) => {
+                let mut result = String::new();
+                for part in parts {
+                    match part {
+                        FormatPart::Text(text) => result.push_str(text),
+                        FormatPart::Expr(expr, spec) => {
+                            let value = self.ev_expr(expr)?;
+                            let Some(text) = value.format(spec) else {
+                                return EvaluatioError::new(
+                                    ErrorKind::Type,
+                                    format!(
+                                        "Invalid format spec '{}' for {}",
+                                        spec,
+                                        value.type_name()
+                                    ),
+                                )
+                                .at(expr.start, expr.end)
                                 .raise();
-                        }
-                        if self.classes[&class_str]
-                            .variables
-                            .contains_key(&attribute.to_string_value())
-                        {
-                            stack.push(
-                                self.classes[&class_str].variables[&attribute.to_string_value()]
-                                    .clone(),
-                            );
-                        } else if self.classes[&class_str]
-                            .functions
-                            .contains_key(&attribute.to_string_value())
-                        {
-                            let function_name = &attribute.to_string_value();
-                            let mut args: Vec<Value> = vec![];
-                            let function_args = tokens.get(i + 2);
-                            for arg in function_args.unwrap_or(&Value::None).iter() {
-                                if let Value::Str(s) = arg {
-                                    let evaluated_arg = self.ev_expr(s);
-                                    args.push(evaluated_arg);
-                                } else {
-                                    args.push(arg.clone());
-                                }
-                            }
-                            if args.len() == 1
-                                && args[0].to_string_value() == Value::None.to_string_value()
-                            {
-                                args = vec![];
-                            }
-                            // println!("Class function {} called with arguments: {:?}", function_name, args);
-                            let result = self.ev_class_func(
-                                tokens[i - 1].to_string_value(),
-                                function_name,
-                                args,
-                                None,
-                                Some(self.classes[&class_str].clone()),
-                                true,
-                            );
-                            stack.push(result);
-                            i += 1; // Skip the next token which is the argument list
+                            };
+                            result.push_str(&text);
                         }
                     }
-                    Value::ClassStr(class_str) => {
-                        let lib_name = class_str.lib_name;
-                        if !self.ipl_libraries[&lib_name]
-                            .classes
-                            .contains_key(&class_str.class_name)
-                        {
-                            EvaluatioError::new("Left side of '.' is not a class".to_string())
-                                .raise();
-                        }
-                        if self.ipl_libraries[&lib_name].classes[&class_str.class_name]
-                            .variables
-                            .contains_key(&attribute.to_string_value())
-                        {
-                            stack.push(
-                                self.ipl_libraries[&lib_name].classes[&class_str.class_name]
-                                    .variables[&attribute.to_string_value()]
-                                    .clone(),
-                            );
-                        } else if self.ipl_libraries[&lib_name].classes[&class_str.class_name]
-                            .functions
-                            .contains_key(&attribute.to_string_value())
-                        {
-                            let function_name = &attribute.to_string_value();
-                            let mut args: Vec<Value> = vec![];
-                            let function_args = tokens.get(i + 2);
-                            for arg in function_args.unwrap_or(&Value::None).iter() {
-                                if let Value::Str(s) = arg {
-                                    let evaluated_arg = self.ev_expr(s);
-                                    args.push(evaluated_arg);
-                                } else {
-                                    args.push(arg.clone());
-                                }
-                            }
-                            if args.len() == 1
-                                && args[0].to_string_value() == Value::None.to_string_value()
-                            {
-                                args = vec![];
-                            }
-                            // println!("Class function {} called with arguments: {:?}", function_name, args);
-                            let result = self.ev_lib_class_func(
-                                lib_name.clone(),
-                                class_str.class_name.clone(),
-                                tokens[i - 1].to_string_value(),
-                                function_name,
-                                args,
-                                None,
-                                Some(
-                                    self.ipl_libraries[&lib_name].classes[&class_str.class_name]
-                                        .clone(),
-                                ),
-                                true,
-                            );
-                            stack.push(result);
-                            i += 1; // Skip the next token which is the argument list
-                        }
-                    }
-                    _ => EvaluatioError::new("Left side of '.' is not an instance".to_string())
-                        .raise(),
-                }
-                i += 1; // Skip the attribute token
-            } else if matches!(token, Value::IndexValue(_)) {
-                let index_value = match token {
-                    Value::IndexValue(iv) => iv,
-                    _ => unreachable!(),
-                };
-                let list = stack
-                    .pop()
-                    .expect("No list to index")
-                    .as_list()
-                    .unwrap_or_default();
-                let start = index_value.start;
-                let end = index_value.end;
-                // println!("Indexing from {} to {} in list {:?}", start, end, list);
-                if start >= list.len() || end >= list.len() {
-                    EvaluatioError::new("Index out of bounds".to_string()).raise();
-                }
-                if start == end {
-                    stack.push(list[start].clone());
-                } else {
-                    let sublist = list[start..=end].to_vec();
-                    stack.push(Value::List(sublist));
-                }
-                i += 1;
-            } else {
-                let rhs = stack.pop().expect("Not enough values on stack");
-                let lhs = stack.pop().expect("Not enough values on stack");
-
-                if token_str == "+" {
-                    stack.push(lhs + rhs);
-                } else if token_str == "-" {
-                    stack.push(lhs - rhs);
-                } else if token_str == "*" {
-                    stack.push(lhs * rhs);
-                } else if token_str == "/" {
-                    stack.push(lhs / rhs);
-                } else if token_str == "==" {
-                    stack.push(Value::Bool(lhs == rhs));
-                } else if token_str == "!=" {
-                    stack.push(Value::Bool(lhs != rhs));
-                } else if token_str == "<" {
-                    stack.push(Value::Bool(lhs < rhs));
-                } else if token_str == "<=" {
-                    stack.push(Value::Bool(lhs <= rhs));
-                } else if token_str == ">" {
-                    stack.push(Value::Bool(lhs > rhs));
-                } else if token_str == ">=" {
-                    stack.push(Value::Bool(lhs >= rhs));
-                } else if token_str == "and" {
-                    stack.push(Value::Bool(lhs.as_bool() && rhs.as_bool()));
-                } else if token_str == "or" {
-                    stack.push(Value::Bool(lhs.as_bool() || rhs.as_bool()));
                 }
+                Ok(Value::Str(result))
             }
-            i += 1;
         }
-        stack.pop().unwrap_or(Value::None)
     }
 }
@@ -0,0 +1,31 @@
+//! IPL as a library, to embed the interpreter in other Rust programs
+//!
+//! ```
+//! use ipl::{Evaluator, Value};
+//!
+//! let mut evaluator = Evaluator::new();
+//! evaluator.ev_source("def add(a, b)\n    return a + b").unwrap();
+//! evaluator.set_variable("x", Value::Number(2.0));
+//! let result = evaluator.call("add", vec![Value::Number(1.0), Value::Number(2.0)]).unwrap();
+//! assert_eq!(result, Value::Number(3.0));
+//! assert_eq!(evaluator.ev_source("x * 10").unwrap(), Value::Number(20.0));
+//! ```
+mod ast;
+mod built_in_functions;
+mod debug;
+mod evaluator;
+mod library;
+mod parser;
+mod repl;
+mod state;
+mod tokenizer;
+mod value;
+
+pub use debug::{ErrorKind, EvaluatioError, IplResult};
+pub use evaluator::Evaluator;
+pub use value::Value;
+
+/// Start the interactive REPL on stdin and stdout, like running IPL without a file
+pub fn run_repl() -> IplResult<()> {
+    repl::run()
+}
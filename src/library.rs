@@ -2,7 +2,7 @@ use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
 
-use crate::debug::EvaluatioError;
+use crate::debug::{ErrorKind, EvaluatioError, IplResult};
 
 fn get_ili_path() -> PathBuf {
     match std::env::var("ILI_PATH") {
@@ -28,24 +28,23 @@ fn get_hardcoded_ili_path() -> PathBuf {
     }
 }
 
-fn load_library_json(path: &Path) -> String {
-    let raw = fs::read_to_string(path).ok();
+fn load_library_json(path: &Path) -> IplResult<String> {
+    let Ok(raw) = fs::read_to_string(path) else {
+        return EvaluatioError::new(ErrorKind::IO, "Failed to read Library.json file".to_string())
+            .raise();
+    };
 
-    if raw.is_none() {
-        EvaluatioError::new("Failed to read Library.json file".to_string()).raise();
-    }
-
-    for line in raw.unwrap().lines() {
+    for line in raw.lines() {
         let l = line.trim();
 
         if l.starts_with("\"entry\"") {
             let entry = extract_string(l);
             if let Some(entry) = entry {
-                return entry;
+                return Ok(entry);
             }
         }
     }
-    "".to_string()
+    Ok("".to_string())
 }
 
 fn extract_string(line: &str) -> Option<String> {
@@ -59,25 +58,33 @@ fn extract_string(line: &str) -> Option<String> {
     Some(rest[..value_end].to_string()) // Extracted value
 }
 
-pub fn get_library_entry_path(libary_name: &str) -> PathBuf {
+pub fn get_library_entry_path(libary_name: &str) -> IplResult<PathBuf> {
     let libs_dir: PathBuf = get_ili_path().join("libs");
     let path: PathBuf = libs_dir.join(libary_name);
 
     if !path.exists() {
-        EvaluatioError::new("Library doesnt exist".to_string()).raise();
+        return EvaluatioError::new(ErrorKind::Import, "Library doesnt exist".to_string()).raise();
     }
 
     let path_to_config: PathBuf = path.join("Library.json");
     if !path_to_config.exists() {
-        EvaluatioError::new("Library is missing Library.json file".to_string()).raise();
+        return EvaluatioError::new(
+            ErrorKind::Import,
+            "Library is missing Library.json file".to_string(),
+        )
+        .raise();
     }
 
-    let lib_entry: String = load_library_json(&path_to_config);
+    let lib_entry: String = load_library_json(&path_to_config)?;
 
     let entry_path: PathBuf = path.join(lib_entry);
     if !entry_path.exists() {
-        EvaluatioError::new("Library entry file doesnt exist".to_string()).raise();
+        return EvaluatioError::new(
+            ErrorKind::Import,
+            "Library entry file doesnt exist".to_string(),
+        )
+        .raise();
     }
 
-    entry_path
+    Ok(entry_path)
 }
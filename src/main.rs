@@ -1,27 +1,35 @@
 use std::env;
-mod built_in_functions;
-mod debug;
-mod evaluator;
-mod library;
-mod state;
-mod tokenizer;
+use std::process;
+use std::thread;
 
-pub mod value;
+use ipl::{ErrorKind, EvaluatioError, Evaluator, IplResult, run_repl};
 
-use debug::EvaluatioError;
-use evaluator::Evaluator;
+// Deep recursion in IPL needs more than the default stack of the main thread
+const STACK_SIZE: usize = 256 * 1024 * 1024;
 
 fn main() {
     let args: Vec<String> = env::args().collect::<Vec<String>>(); // Collect command line arguments
+    let interpreter = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || run(&args))
+        .expect("failed to start the interpreter thread");
+    let result = interpreter.join().unwrap_or_else(|_| process::exit(101));
+    if let Err(error) = result
+        && error.kind != ErrorKind::Quit
+    {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> IplResult<()> {
     if args.len() < 2 {
-        EvaluatioError::new("Please provide a file to evaluate".to_string()).raise();
-        return;
+        return run_repl(); // Start the REPL when no file is given
     }
     let file = &args[1]; // Get the file name from arguments
     if !file.ends_with(".ipl") {
-        EvaluatioError::new("File must be a IPL file".to_string()).raise();
-        return;
+        return EvaluatioError::new(ErrorKind::IO, "File must be a IPL file".to_string()).raise();
     }
     let mut evaluator: Evaluator = Evaluator::new(); // Create a new evaluator for the file
-    evaluator.ev_file(file); // Evaluate the file
+    evaluator.ev_file(file) // Evaluate the file
 }
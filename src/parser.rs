@@ -0,0 +1,880 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use once_cell::sync::Lazy;
+
+use crate::ast::{Expr, ExprKind, FormatPart, NamedExprs, Stmt, StmtKind};
+use crate::debug::{ErrorKind, EvaluatioError, IplResult};
+use crate::state;
+use crate::tokenizer::{Token, Tokenizer, opens_multiline_string, strip_comment};
+
+static PREC: Lazy<HashMap<&str, i32>> = Lazy::new(|| {
+    HashMap::from([
+        ("or", 1),
+        ("and", 2),
+        ("==", 4),
+        ("!=", 4),
+        ("<", 4),
+        ("<=", 4),
+        (">", 4),
+        (">=", 4),
+        ("in", 4),
+        ("+", 5),
+        ("-", 5),
+        ("*", 6),
+        ("/", 6),
+        ("//", 6),
+        ("%", 6),
+        ("**", 8),
+    ])
+});
+
+// `not` binds looser than comparisons, unary `-` and `+` tighter than `*` and `/`
+// but looser than `**`, so -2 ** 2 is -(2 ** 2)
+const NOT_PRECEDENCE: i32 = 3;
+const UNARY_PRECEDENCE: i32 = 7;
+
+const KEYWORDS: [&str; 4] = ["and", "or", "not", "lambda"];
+
+/// A non-empty source line with its comment removed
+struct Line {
+    line_number: usize,
+    indentation: usize,
+    content: String,
+    tokens: Vec<Token>,
+}
+
+fn get_indentation(line: &str) -> usize {
+    line.chars().take_while(|c| c.is_whitespace()).count()
+}
+
+fn is_identifier(token: &str) -> bool {
+    token
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+        && !KEYWORDS.contains(&token)
+}
+
+// Numbers start with a digit or with a '.' followed by a digit, so inf or nan are names
+fn starts_number(token: &str) -> bool {
+    let mut bytes = token.bytes();
+    match bytes.next() {
+        Some(b'.') => bytes.next().is_some_and(|byte| byte.is_ascii_digit()),
+        Some(first) => first.is_ascii_digit(),
+        None => false,
+    }
+}
+
+fn syntax_error<T>(message: String) -> IplResult<T> {
+    EvaluatioError::new(ErrorKind::Syntax, message).raise()
+}
+
+// Raise a syntax error pointing at a token
+fn syntax_error_at<T>(token: &Token, message: String) -> IplResult<T> {
+    EvaluatioError::new(ErrorKind::Syntax, message)
+        .at(token.column, token.end())
+        .raise()
+}
+
+// Turns the source of a file into a list of statements, using indentation for blocks
+pub struct Parser {
+    lines: Vec<Line>,
+    position: usize,
+}
+
+impl Parser {
+    pub fn new(source: &str) -> IplResult<Self> {
+        let tokenizer = Tokenizer::new();
+        let mut lines = vec![];
+        let mut raw_lines = source.lines().enumerate();
+        while let Some((index, raw_line)) = raw_lines.next() {
+            let mut raw_line = raw_line.to_string();
+            // A triple-quoted string continues on the following lines until it is closed
+            while opens_multiline_string(&raw_line) {
+                let Some((_, next_line)) = raw_lines.next() else {
+                    break;
+                };
+                raw_line.push('\n');
+                raw_line.push_str(next_line);
+            }
+            let line = strip_comment(&raw_line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            state::set_programm_state(index + 1, line.trim_end());
+            lines.push(Line {
+                line_number: index + 1,
+                indentation: get_indentation(line),
+                content: line.trim_end().to_string(),
+                tokens: tokenizer.tokenize(line)?,
+            });
+        }
+        Ok(Self { lines, position: 0 })
+    }
+
+    // Like parse, but skips the docstring at the top of a file
+    pub fn parse_file(&mut self) -> IplResult<Vec<Stmt>> {
+        Ok(skip_docstring(self.parse()?))
+    }
+
+    pub fn parse(&mut self) -> IplResult<Vec<Stmt>> {
+        if self.lines.is_empty() {
+            return Ok(vec![]);
+        }
+        let body = self.parse_block()?;
+        if self.position < self.lines.len() {
+            return self.indentation_error();
+        }
+        Ok(body)
+    }
+
+    // Raise an error pointing at the indentation of the current line
+    fn indentation_error<T>(&self) -> IplResult<T> {
+        self.set_state(self.position);
+        let indentation = self.lines[self.position].indentation;
+        EvaluatioError::new(ErrorKind::Syntax, "Unexpected indentation".to_string())
+            .at(0, indentation)
+            .raise()
+    }
+
+    fn set_state(&self, position: usize) {
+        let line = &self.lines[position];
+        state::set_programm_state(line.line_number, &line.content);
+    }
+
+    // Parse all following lines that share the indentation of the first one
+    fn parse_block(&mut self) -> IplResult<Vec<Stmt>> {
+        let indentation = self.lines[self.position].indentation;
+        let mut body = vec![];
+        while let Some(line) = self.lines.get(self.position) {
+            if line.indentation < indentation {
+                break;
+            }
+            if line.indentation > indentation {
+                return self.indentation_error();
+            }
+            body.push(self.parse_statement()?);
+        }
+        Ok(body)
+    }
+
+    // Parse the body of a function or class, without its docstring
+    fn parse_documented_body(&mut self, header_indentation: usize) -> IplResult<Vec<Stmt>> {
+        Ok(skip_docstring(self.parse_body(header_indentation)?))
+    }
+
+    // Parse the indented block following a header (def, if, while, ...)
+    fn parse_body(&mut self, header_indentation: usize) -> IplResult<Vec<Stmt>> {
+        match self.lines.get(self.position) {
+            Some(line) if line.indentation > header_indentation => self.parse_block(),
+            _ => syntax_error("Expected an indented block".to_string()),
+        }
+    }
+
+    // Check if the next line is on the given indentation and starts with the keyword
+    fn next_line_starts_with(&self, indentation: usize, keyword: &str) -> bool {
+        self.lines
+            .get(self.position)
+            .is_some_and(|line| line.indentation == indentation && line.tokens[0].value == keyword)
+    }
+
+    fn parse_statement(&mut self) -> IplResult<Stmt> {
+        self.set_state(self.position);
+        let line = &self.lines[self.position];
+        let line_number = line.line_number;
+        let line_content = line.content.clone();
+        let indentation = line.indentation;
+        let tokens = line.tokens.clone();
+        self.position += 1;
+
+        let kind = match tokens[0].value.as_str() {
+            "use" | "import" => {
+                let name = line_content.trim()[tokens[0].value.len()..].trim();
+                if name.is_empty() {
+                    return syntax_error(format!("{} requires a name", tokens[0].value));
+                }
+                if tokens[0].value == "use" {
+                    StmtKind::Use(name.to_string())
+                } else {
+                    StmtKind::Import(name.to_string())
+                }
+            }
+            "if" => {
+                let mut branches = vec![(
+                    parse_expression(&tokens[1..])?,
+                    self.parse_body(indentation)?,
+                )];
+                let mut else_body = None;
+                while self.next_line_starts_with(indentation, "elif") {
+                    self.set_state(self.position);
+                    let condition = parse_expression(&self.lines[self.position].tokens[1..])?;
+                    self.position += 1;
+                    branches.push((condition, self.parse_body(indentation)?));
+                }
+                if self.next_line_starts_with(indentation, "else") {
+                    self.set_state(self.position);
+                    expect_end(&self.lines[self.position].tokens, 1)?;
+                    self.position += 1;
+                    else_body = Some(self.parse_body(indentation)?);
+                }
+                StmtKind::If {
+                    branches,
+                    else_body,
+                }
+            }
+            "elif" | "else" => return syntax_error(format!("{} without if", tokens[0].value)),
+            "while" => {
+                let condition = parse_expression(&tokens[1..])?;
+                StmtKind::While(condition, self.parse_body(indentation)?)
+            }
+            "for" => {
+                let variable_name = match tokens.get(1) {
+                    Some(token) if is_identifier(&token.value) => token.value.clone(),
+                    Some(token) => {
+                        return syntax_error_at(token, "for requires a variable name".to_string());
+                    }
+                    None => return syntax_error("for requires a variable name".to_string()),
+                };
+                match tokens.get(2) {
+                    Some(token) if token.value == "in" => {}
+                    Some(token) => {
+                        return syntax_error_at(
+                            token,
+                            "Expected 'in' after the for variable".to_string(),
+                        );
+                    }
+                    None => {
+                        return syntax_error("Expected 'in' after the for variable".to_string());
+                    }
+                }
+                let iterable = parse_expression(&tokens[3..])?;
+                StmtKind::For(variable_name, iterable, self.parse_body(indentation)?)
+            }
+            "break" => {
+                expect_end(&tokens, 1)?;
+                StmtKind::Break
+            }
+            "continue" => {
+                expect_end(&tokens, 1)?;
+                StmtKind::Continue
+            }
+            "return" => {
+                if tokens.len() > 1 {
+                    StmtKind::Return(Some(parse_expression(&tokens[1..])?))
+                } else {
+                    StmtKind::Return(None)
+                }
+            }
+            "global" => StmtKind::Global(parse_names(&tokens[1..])?),
+            "nonlocal" => StmtKind::Nonlocal(parse_names(&tokens[1..])?),
+            "def" => {
+                let name = match tokens.get(1) {
+                    Some(token) if is_identifier(&token.value) => token.value.clone(),
+                    Some(token) => {
+                        return syntax_error_at(token, "Invalid function name".to_string());
+                    }
+                    None => return syntax_error("def requires a function decleration".to_string()),
+                };
+                let (arguments, defaults) = parse_parameters(&tokens[2..])?;
+                StmtKind::Def {
+                    name,
+                    arguments,
+                    defaults,
+                    body: Rc::new(self.parse_documented_body(indentation)?),
+                }
+            }
+            "class" => {
+                let name = match tokens.get(1) {
+                    Some(token) if is_identifier(&token.value) => token.value.clone(),
+                    Some(token) => {
+                        return syntax_error_at(token, "Invalid class name".to_string());
+                    }
+                    None => return syntax_error("class requires a class name".to_string()),
+                };
+                let base = match tokens.get(2) {
+                    None => None,
+                    Some(colon) if colon.value == ":" => match tokens.get(3) {
+                        Some(token) if is_identifier(&token.value) => {
+                            expect_end(&tokens, 4)?;
+                            Some(token.value.clone())
+                        }
+                        _ => {
+                            return syntax_error_at(
+                                colon,
+                                "Expected a base class after ':'".to_string(),
+                            );
+                        }
+                    },
+                    Some(other) => {
+                        return syntax_error_at(other, format!("Unexpected token {}", other.value));
+                    }
+                };
+                StmtKind::Class {
+                    name,
+                    base,
+                    body: self.parse_documented_body(indentation)?,
+                }
+            }
+            _ => match find_assignment(&tokens) {
+                Some(position) => {
+                    let target = parse_expression(&tokens[..position])?;
+                    if !is_assignable(&target) {
+                        return EvaluatioError::new(
+                            ErrorKind::Syntax,
+                            "Cannot assign to this expression".to_string(),
+                        )
+                        .at(target.start, target.end)
+                        .raise();
+                    }
+                    let value = parse_expression(&tokens[position + 1..])?;
+                    match tokens[position].value.strip_suffix('=') {
+                        Some(operator) if !operator.is_empty() => {
+                            StmtKind::AugAssign(target, operator.to_string(), value)
+                        }
+                        _ => StmtKind::Assign(target, value),
+                    }
+                }
+                None => StmtKind::Expr(parse_expression(&tokens)?),
+            },
+        };
+
+        Ok(Stmt {
+            kind,
+            line_number,
+            line_content,
+        })
+    }
+}
+
+// A string as the first statement of a body is its docstring and is not evaluated
+fn skip_docstring(mut body: Vec<Stmt>) -> Vec<Stmt> {
+    if let Some(first) = body.first()
+        && matches!(&first.kind, StmtKind::Expr(Expr { kind: ExprKind::Str(_), .. }))
+    {
+        body.remove(0);
+    }
+    body
+}
+
+fn expect_end(tokens: &[Token], length: usize) -> IplResult<()> {
+    match tokens.get(length) {
+        Some(token) => syntax_error_at(token, format!("Unexpected token {}", token.value)),
+        None => Ok(()),
+    }
+}
+
+// Find the position of an '=' or a compound assignment like '+=' that is not inside brackets
+fn find_assignment(tokens: &[Token]) -> Option<usize> {
+    let mut depth = 0;
+    for (position, token) in tokens.iter().enumerate() {
+        match token.value.as_str() {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => depth -= 1,
+            "=" | "+=" | "-=" | "*=" | "/=" | "//=" | "%=" | "**=" if depth == 0 => {
+                return Some(position);
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// Check if an expression can be on the left side of =
+fn is_assignable(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Variable(_) | ExprKind::Attribute(_, _) => true,
+        ExprKind::Index { target, .. } => is_assignable(target),
+        _ => false,
+    }
+}
+
+// Parse a comma separated list of names, like after global
+fn parse_names(tokens: &[Token]) -> IplResult<Vec<String>> {
+    let mut names = vec![];
+    for (index, token) in tokens.iter().enumerate() {
+        let expected_name = index % 2 == 0;
+        if expected_name && is_identifier(&token.value) {
+            names.push(token.value.clone());
+        } else if expected_name || token.value != "," {
+            return syntax_error_at(token, format!("Unexpected token {}", token.value));
+        }
+    }
+    match tokens.last() {
+        None => syntax_error("Expected a name".to_string()),
+        Some(token) if token.value == "," => {
+            syntax_error_at(token, "Expected a name after ','".to_string())
+        }
+        Some(_) => Ok(names),
+    }
+}
+
+// Parse a parameter list like (a, b, c=1) into the names and the default values
+fn parse_parameters(tokens: &[Token]) -> IplResult<(Vec<String>, NamedExprs)> {
+    match tokens.first() {
+        Some(token) if token.value == "(" => {}
+        Some(token) => {
+            return syntax_error_at(token, "Expected ( after the function name".to_string());
+        }
+        None => return syntax_error("Expected ( after the function name".to_string()),
+    }
+    let mut parameters = vec![];
+    let mut defaults = vec![];
+    let mut position = 1;
+    while let Some(token) = tokens.get(position) {
+        if token.value == ")" {
+            expect_end(tokens, position + 1)?;
+            return Ok((parameters, defaults));
+        }
+        if !is_identifier(&token.value) {
+            return syntax_error_at(token, format!("Invalid parameter name {}", token.value));
+        }
+        if parameters.contains(&token.value) {
+            return syntax_error_at(token, format!("Duplicate parameter {}", token.value));
+        }
+        parameters.push(token.value.clone());
+        position += 1;
+        if tokens.get(position).is_some_and(|next| next.value == "=") {
+            let mut parser = ExpressionParser {
+                tokens: &tokens[position + 1..],
+                position: 0,
+            };
+            defaults.push((token.value.clone(), parser.parse_binary(0)?));
+            position += parser.position + 1;
+        } else if !defaults.is_empty() {
+            return syntax_error_at(
+                token,
+                format!("Parameter {} without a default follows one with a default", token.value),
+            );
+        }
+        match tokens.get(position) {
+            Some(token) if token.value == "," => position += 1,
+            Some(token) if token.value == ")" => {}
+            Some(token) => {
+                return syntax_error_at(token, "Expected , or ) in the parameter list".to_string());
+            }
+            None => return syntax_error("Expected , or ) in the parameter list".to_string()),
+        }
+    }
+    syntax_error("Mismatched parentheses".to_string())
+}
+
+pub fn parse_expression(tokens: &[Token]) -> IplResult<Expr> {
+    if tokens.is_empty() {
+        return syntax_error("Expected an expression".to_string());
+    }
+    let mut parser = ExpressionParser {
+        tokens,
+        position: 0,
+    };
+    let expr = parser.parse_binary(0)?;
+    if let Some(token) = parser.peek() {
+        return syntax_error_at(token, format!("Unexpected token {}", token.value));
+    }
+    Ok(expr)
+}
+
+// Split an f-string into text and the expressions in braces, {{ and }} are literal braces
+fn parse_format_string(token: &Token) -> IplResult<Vec<FormatPart>> {
+    let chars: Vec<char> = token.text().chars().collect();
+    let text_column = token.text_column();
+    let error_at = |position: usize, message: &str| {
+        let column = text_column + position;
+        EvaluatioError::new(ErrorKind::Syntax, message.to_string())
+            .at(column, column + 1)
+            .raise()
+    };
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut text_start = 0;
+    let mut position = 0;
+    while position < chars.len() {
+        let next = chars.get(position + 1);
+        match chars[position] {
+            '{' if next == Some(&'{') => text.push('{'),
+            '}' if next == Some(&'}') => text.push('}'),
+            '}' => return error_at(position, "Single '}' in f-string"),
+            '{' => {
+                if !text.is_empty() {
+                    parts.push(FormatPart::Text(token.unescape(&text, text_start)?));
+                    text.clear();
+                }
+                let (expr, spec, end) = parse_format_field(&chars, position + 1, text_column)?;
+                let Some(expr) = expr else {
+                    return error_at(position, "Expected an expression in f-string");
+                };
+                parts.push(FormatPart::Expr(expr, spec));
+                position = end + 1;
+                text_start = position;
+                continue;
+            }
+            c => {
+                text.push(c);
+                position += 1;
+                continue;
+            }
+        }
+        position += 2;
+    }
+    if !text.is_empty() {
+        parts.push(FormatPart::Text(token.unescape(&text, text_start)?));
+    }
+    Ok(parts)
+}
+
+// Parse the expression and format spec of an f-string field starting after its '{'
+// Returns the position of the closing '}'
+fn parse_format_field(
+    chars: &[char],
+    start: usize,
+    text_column: usize,
+) -> IplResult<(Option<Expr>, String, usize)> {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut spec_start = None;
+    let mut end = None;
+    for (position, &c) in chars.iter().enumerate().skip(start) {
+        match (c, quote) {
+            (c, Some(open)) if c == open => quote = None,
+            (_, Some(_)) => {}
+            ('"' | '\'', None) => quote = Some(c),
+            ('(' | '[' | '{', None) => depth += 1,
+            (')' | ']', None) => depth -= 1,
+            ('}', None) if depth > 0 => depth -= 1,
+            ('}', None) => {
+                end = Some(position);
+                break;
+            }
+            (':', None) if depth == 0 && spec_start.is_none() => spec_start = Some(position),
+            _ => {}
+        }
+    }
+    let Some(end) = end else {
+        let column = text_column + start - 1;
+        return EvaluatioError::new(ErrorKind::Syntax, "Expected '}' in f-string".to_string())
+            .at(column, column + 1)
+            .raise();
+    };
+    let expr_end = spec_start.unwrap_or(end);
+    let source: String = chars[start..expr_end].iter().collect();
+    let spec: String = match spec_start {
+        Some(spec_start) => chars[spec_start + 1..end].iter().collect(),
+        None => String::new(),
+    };
+    // Columns of the tokens are moved to where the expression is in the line
+    let offset = text_column + start;
+    let mut tokens = Tokenizer::new().tokenize(&source).map_err(|mut error| {
+        error.column = error.column.map(|column| column + offset);
+        error
+    })?;
+    if tokens.is_empty() {
+        return Ok((None, spec, end));
+    }
+    for token in tokens.iter_mut() {
+        token.column += offset;
+    }
+    Ok((Some(parse_expression(&tokens)?), spec, end))
+}
+
+// Parses a single expression from tokens, using precedence climbing for operators
+struct ExpressionParser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl ExpressionParser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn peek_is(&self, value: &str) -> bool {
+        self.peek().is_some_and(|token| token.value == value)
+    }
+
+    fn next(&mut self) -> IplResult<Token> {
+        match self.tokens.get(self.position) {
+            Some(token) => {
+                self.position += 1;
+                Ok(token.clone())
+            }
+            None => {
+                // Point right behind the last token
+                let end = self.tokens.last().map_or(0, |token| token.end());
+                EvaluatioError::new(
+                    ErrorKind::Syntax,
+                    "Unexpected end of expression".to_string(),
+                )
+                .at(end, end + 1)
+                .raise()
+            }
+        }
+    }
+
+    fn expect(&mut self, value: &str) -> IplResult<Token> {
+        let token = self.next()?;
+        if token.value != value {
+            return syntax_error_at(
+                &token,
+                format!("Expected {} but found {}", value, token.value),
+            );
+        }
+        Ok(token)
+    }
+
+    fn parse_binary(&mut self, min_precedence: i32) -> IplResult<Expr> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(token) = self.peek() {
+            let Some(&precedence) = PREC.get(token.value.as_str()) else {
+                break;
+            };
+            if precedence < min_precedence {
+                break;
+            }
+            let operator = self.next()?.value;
+            // ** is right-associative: 2 ** 3 ** 2 is 2 ** (3 ** 2)
+            let right_precedence = if operator == "**" { precedence } else { precedence + 1 };
+            let rhs = self.parse_binary(right_precedence)?;
+            let (start, end) = (lhs.start, rhs.end);
+            lhs = Expr {
+                kind: ExprKind::BinaryOp(Box::new(lhs), operator, Box::new(rhs)),
+                start,
+                end,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> IplResult<Expr> {
+        let precedence = match self.peek().map(|token| token.value.as_str()) {
+            Some("not") => NOT_PRECEDENCE,
+            Some("-" | "+") => UNARY_PRECEDENCE,
+            Some("lambda") => return self.parse_lambda(),
+            _ => return self.parse_postfix(),
+        };
+        let operator = self.next()?;
+        if let Some(expr) = self.parse_smallest_int(&operator) {
+            return Ok(expr);
+        }
+        let operand = self.parse_binary(precedence)?;
+        let end = operand.end;
+        Ok(Expr {
+            kind: ExprKind::UnaryOp(operator.value, Box::new(operand)),
+            start: operator.column,
+            end,
+        })
+    }
+
+    // The smallest int has no positive counterpart, so -9223372036854775808 is one literal
+    fn parse_smallest_int(&mut self, operator: &Token) -> Option<Expr> {
+        let digits = self.peek()?;
+        let followed_by_power = self
+            .tokens
+            .get(self.position + 1)
+            .is_some_and(|token| token.value == "**");
+        if operator.value != "-" || digits.value.parse::<i64>().is_ok() || followed_by_power {
+            return None;
+        }
+        let number = format!("-{}", digits.value).parse::<i64>().ok()?;
+        let end = self.next().ok()?.end();
+        Some(Expr {
+            kind: ExprKind::Int(number),
+            start: operator.column,
+            end,
+        })
+    }
+
+    // A lambda like `lambda x, y: x + y`, its body extends as far as possible
+    fn parse_lambda(&mut self) -> IplResult<Expr> {
+        let keyword = self.next()?;
+        let Some(colon) = self.tokens[self.position..]
+            .iter()
+            .position(|token| token.value == ":")
+        else {
+            return syntax_error_at(&keyword, "Expected ':' after lambda parameters".to_string());
+        };
+        let parameters = &self.tokens[self.position..self.position + colon];
+        let parameters = if parameters.is_empty() {
+            vec![]
+        } else {
+            parse_names(parameters)?
+        };
+        self.position += colon + 1;
+        let body = self.parse_binary(0)?;
+        let end = body.end;
+        Ok(Expr {
+            kind: ExprKind::Lambda(parameters, Box::new(body)),
+            start: keyword.column,
+            end,
+        })
+    }
+
+    fn parse_postfix(&mut self) -> IplResult<Expr> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            let start = expr.start;
+            let (kind, end) = if self.peek_is(".") {
+                self.position += 1;
+                let attribute = self.next()?;
+                if !is_identifier(&attribute.value) {
+                    return syntax_error_at(&attribute, "Expected attribute after '.'".to_string());
+                }
+                let end = attribute.end();
+                (ExprKind::Attribute(Box::new(expr), attribute.value), end)
+            } else if self.peek_is("(") {
+                self.position += 1;
+                let (arguments, keywords, end) = self.parse_arguments()?;
+                (ExprKind::Call(Box::new(expr), arguments, keywords), end)
+            } else if self.peek_is("[") {
+                self.position += 1;
+                self.parse_index(expr)?
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr { kind, start, end };
+        }
+    }
+
+    // Parse the inside of [] after a value, either a single index or a slice (start:end:step)
+    fn parse_index(&mut self, target: Expr) -> IplResult<(ExprKind, usize)> {
+        let start = if self.peek_is(":") {
+            None
+        } else {
+            Some(Box::new(self.parse_binary(0)?))
+        };
+        let (mut end, mut step) = (None, None);
+        let slice = self.peek_is(":");
+        if slice {
+            self.position += 1;
+            if !self.peek_is("]") && !self.peek_is(":") {
+                end = Some(Box::new(self.parse_binary(0)?));
+            }
+            if self.peek_is(":") {
+                self.position += 1;
+                if !self.peek_is("]") {
+                    step = Some(Box::new(self.parse_binary(0)?));
+                }
+            }
+        }
+        let closing = self.expect("]")?;
+        let kind = ExprKind::Index {
+            target: Box::new(target),
+            start,
+            end,
+            step,
+            slice,
+        };
+        Ok((kind, closing.end()))
+    }
+
+    // Parse comma separated expressions until the closing token, returning where it ends
+    fn parse_list(&mut self, closing: &str) -> IplResult<(Vec<Expr>, usize)> {
+        let mut elements = vec![];
+        while !self.peek_is(closing) {
+            elements.push(self.parse_binary(0)?);
+            if !self.peek_is(closing) {
+                self.expect(",")?;
+            }
+        }
+        let end = self.next()?.end();
+        Ok((elements, end))
+    }
+
+    // Parse the arguments of a call after the (, keyword arguments like `name=value` come last
+    fn parse_arguments(&mut self) -> IplResult<(Vec<Expr>, NamedExprs, usize)> {
+        let mut arguments = vec![];
+        let mut keywords: NamedExprs = vec![];
+        while !self.peek_is(")") {
+            let is_keyword = self.peek().is_some_and(|token| is_identifier(&token.value))
+                && self
+                    .tokens
+                    .get(self.position + 1)
+                    .is_some_and(|token| token.value == "=");
+            if is_keyword {
+                let name = self.next()?;
+                if keywords.iter().any(|(keyword, _)| *keyword == name.value) {
+                    return syntax_error_at(
+                        &name,
+                        format!("Duplicate keyword argument {}", name.value),
+                    );
+                }
+                self.position += 1;
+                keywords.push((name.value, self.parse_binary(0)?));
+            } else {
+                let argument = self.parse_binary(0)?;
+                if !keywords.is_empty() {
+                    return EvaluatioError::new(
+                        ErrorKind::Syntax,
+                        "Positional argument after keyword arguments".to_string(),
+                    )
+                    .at(argument.start, argument.end)
+                    .raise();
+                }
+                arguments.push(argument);
+            }
+            if !self.peek_is(")") {
+                self.expect(",")?;
+            }
+        }
+        let end = self.next()?.end();
+        Ok((arguments, keywords, end))
+    }
+
+    // Parse the key value pairs of a map literal after the {
+    fn parse_map(&mut self) -> IplResult<(Vec<(Expr, Expr)>, usize)> {
+        let mut entries = vec![];
+        while !self.peek_is("}") {
+            let key = self.parse_binary(0)?;
+            self.expect(":")?;
+            entries.push((key, self.parse_binary(0)?));
+            if !self.peek_is("}") {
+                self.expect(",")?;
+            }
+        }
+        let end = self.next()?.end();
+        Ok((entries, end))
+    }
+
+    fn parse_primary(&mut self) -> IplResult<Expr> {
+        let token = self.next()?;
+        let value = token.value.as_str();
+        let (kind, end) = if value == "(" {
+            let expr = self.parse_binary(0)?;
+            self.expect(")")?;
+            return Ok(expr);
+        } else if value == "[" {
+            let (elements, end) = self.parse_list("]")?;
+            (ExprKind::List(elements), end)
+        } else if value == "{" {
+            let (entries, end) = self.parse_map()?;
+            (ExprKind::Map(entries), end)
+        } else if token.is_format_string() {
+            (ExprKind::FormatString(parse_format_string(&token)?), token.end())
+        } else if token.is_string() {
+            (ExprKind::Str(token.string_value()?), token.end())
+        } else if starts_number(value) {
+            if let Ok(number) = value.parse::<i64>() {
+                (ExprKind::Int(number), token.end())
+            } else if value.bytes().all(|byte| byte.is_ascii_digit()) {
+                return EvaluatioError::new(
+                    ErrorKind::Overflow,
+                    format!("Integer literal {} is too large for an int", value),
+                )
+                .at(token.column, token.end())
+                .raise();
+            } else if let Ok(number) = value.parse::<f64>() {
+                (ExprKind::Number(number), token.end())
+            } else {
+                return syntax_error_at(&token, format!("Unexpected token {}", value));
+            }
+        } else if is_identifier(value) {
+            (ExprKind::Variable(value.to_string()), token.end())
+        } else {
+            return syntax_error_at(&token, format!("Unexpected token {}", value));
+        };
+        Ok(Expr {
+            kind,
+            start: token.column,
+            end,
+        })
+    }
+}
@@ -0,0 +1,94 @@
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+
+use crate::debug::{ErrorKind, EvaluatioError, IplResult};
+use crate::evaluator::Evaluator;
+use crate::tokenizer::opens_multiline_string;
+use crate::value::Value;
+
+// Lines starting with one of these keywords need an indented block after them
+const BLOCK_KEYWORDS: [&str; 7] = ["def", "class", "if", "elif", "else", "while", "for"];
+
+fn starts_block(line: &str) -> bool {
+    let keyword: String = line
+        .trim_start()
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect();
+    BLOCK_KEYWORDS.contains(&keyword.as_str())
+}
+
+// Evaluate one complete input and print the value of a bare expression,
+// returns false when the input called quit()
+fn evaluate(evaluator: &mut Evaluator, source: &str) -> bool {
+    match evaluator.ev_named_source("<repl>", source) {
+        Ok(Value::None) => {}
+        Ok(value) => println!("{}", value.to_string_value()),
+        Err(error) if error.kind == ErrorKind::Quit => return false,
+        Err(error) => eprintln!("{}", error),
+    }
+    true
+}
+
+/// Read-eval-print loop, started when IPL is run without a file
+/// Blocks (def, class, if, ...) are read until an empty line
+pub(crate) fn run() -> IplResult<()> {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(error) => {
+            return EvaluatioError::new(
+                ErrorKind::IO,
+                format!("Could not start the REPL: {}", error),
+            )
+            .raise();
+        }
+    };
+    let mut evaluator = Evaluator::new();
+    println!(
+        "IPL {} (type quit() or press Ctrl+D to exit)",
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let mut source = String::new();
+    loop {
+        let prompt = if source.is_empty() { ">>> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                if source.is_empty() && line.trim().is_empty() {
+                    continue;
+                }
+                source += &line;
+                source.push('\n');
+                // Keep reading while a triple-quoted string is open,
+                // or until a block is closed with an empty line
+                if opens_multiline_string(&source)
+                    || (starts_block(&source) && !line.trim().is_empty())
+                {
+                    continue;
+                }
+                if !evaluate(&mut evaluator, &source) {
+                    return Ok(());
+                }
+                source.clear();
+            }
+            // Ctrl+C discards the current input
+            Err(ReadlineError::Interrupted) => source.clear(),
+            Err(ReadlineError::Eof) => {
+                if !source.is_empty() {
+                    evaluate(&mut evaluator, &source);
+                }
+                return Ok(());
+            }
+            Err(error) => {
+                return EvaluatioError::new(
+                    ErrorKind::IO,
+                    format!("Could not read input: {}", error),
+                )
+                .raise();
+            }
+        }
+    }
+}
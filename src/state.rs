@@ -1,32 +1,105 @@
-use std::sync::RwLock;
+use std::cell::RefCell;
 
-/// Holds the current line number and content
-pub struct LineData {
+/// A running function (or file) and the line it is currently at
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub function_name: String,
+    pub class_name: String,
+    pub lib_name: String,
+    pub file_path: String,
     pub line_number: usize,
     pub line_content: String,
 }
 
-// Global, thread-safe state
-static PROGRAMM_STATE: RwLock<LineData> = RwLock::new(LineData {
-    line_number: 0,
-    line_content: String::new(),
-});
+impl Frame {
+    fn new(function_name: &str, class_name: &str, lib_name: &str, file_path: &str) -> Self {
+        Self {
+            function_name: function_name.to_string(),
+            class_name: class_name.to_string(),
+            lib_name: lib_name.to_string(),
+            file_path: file_path.to_string(),
+            line_number: 0,
+            line_content: String::new(),
+        }
+    }
+
+    /// Name of the frame as shown in a traceback, like `examplelib.Test.hello_world`
+    pub fn describe(&self) -> String {
+        let mut name = self.function_name.clone();
+        if !self.class_name.is_empty() {
+            name = format!("{}.{}", self.class_name, name);
+        }
+        if !self.lib_name.is_empty() {
+            name = format!("{}.{}", self.lib_name, name);
+        }
+        name
+    }
+}
+
+// Call stack of the current thread, the last frame is the one currently running
+// Each thread has its own, so interpreters embedded in different threads do not mix
+thread_local! {
+    static CALL_STACK: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+}
+
+// Run a closure on the current frame, creating the <module> frame if there is none
+fn with_current_frame<T>(f: impl FnOnce(&mut Frame) -> T) -> T {
+    CALL_STACK.with_borrow_mut(|stack| {
+        if stack.is_empty() {
+            stack.push(Frame::new("<module>", "", "", ""));
+        }
+        f(stack.last_mut().unwrap())
+    })
+}
+
+/// Public getter for the file path
+pub fn get_file_path() -> String {
+    with_current_frame(|frame| frame.file_path.clone())
+}
 
 /// Public getter for line number
 pub fn get_line_number() -> usize {
-    let state = PROGRAMM_STATE.read().unwrap();
-    state.line_number
+    with_current_frame(|frame| frame.line_number)
 }
 
 /// Public getter for line content
 pub fn get_line_content() -> String {
-    let state = PROGRAMM_STATE.read().unwrap();
-    state.line_content.clone()
+    with_current_frame(|frame| frame.line_content.clone())
+}
+
+/// Public getter for all active frames, the outermost first
+pub fn get_call_stack() -> Vec<Frame> {
+    CALL_STACK.with_borrow(|stack| stack.clone())
+}
+
+/// Public getter for the number of active frames
+pub fn get_call_depth() -> usize {
+    with_current_frame(|_| {});
+    CALL_STACK.with_borrow(|stack| stack.len())
 }
 
 /// Crate-private setter: only accessible inside this crate (e.g., from `evaluator.rs`)
 pub fn set_programm_state(line_number: usize, line_content: &str) {
-    let mut state = PROGRAMM_STATE.write().unwrap();
-    state.line_number = line_number;
-    state.line_content = line_content.to_string();
+    with_current_frame(|frame| {
+        frame.line_number = line_number;
+        frame.line_content = line_content.to_string();
+    });
+}
+
+/// Set the file that is currently evaluated
+pub fn set_file_path(file_path: &str) {
+    with_current_frame(|frame| frame.file_path = file_path.to_string());
+}
+
+/// Enter a function, every push has to be followed by a pop_frame
+pub fn push_frame(function_name: &str, class_name: &str, lib_name: &str, file_path: &str) {
+    with_current_frame(|_| {});
+    CALL_STACK.with_borrow_mut(|stack| {
+        stack.push(Frame::new(function_name, class_name, lib_name, file_path));
+    });
+}
+
+/// Leave the current function
+pub fn pop_frame() {
+    CALL_STACK.with_borrow_mut(|stack| stack.pop());
 }
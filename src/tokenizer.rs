@@ -1,295 +1,215 @@
-use crate::built_in_functions::BUILT_IN_FUNCTIONS;
-use crate::debug::EvaluatioError;
-use crate::value::{Class, IPL_Library};
-use crate::value::{IndexValue, Value};
+use crate::debug::{ErrorKind, EvaluatioError, IplResult};
 use regex::Regex;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
 
 
 static TOKEN_PATTERN: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r#""[^"]*"|'[^']*'|==|!=|<=|>=|[+\-*/=()<>\[\],:]|\.|\band\b|\bor\b|\bnot\b|[a-zA-Z_]\w*|\d+\.\d+|\d+"#).unwrap()
+    Regex::new(r#"[fF]?"""(?:[^\\]|\\[\s\S])*?"""|[fF]?'''(?:[^\\]|\\[\s\S])*?'''|[fF]?"(?:[^"\\]|\\.)*"|[fF]?'(?:[^'\\]|\\.)*'|==|!=|<=|>=|\*\*=|//=|[+\-*/%]=|\*\*|//|[+\-*/%=()<>\[\]{},:]|\.|\band\b|\bor\b|\bnot\b|[a-zA-Z_]\w*|\d+\.\d+|\d+"#).unwrap()
 });
 
-static PREC: Lazy<HashMap<String, i32>> = Lazy::new(|| {
-    HashMap::from([
-            ("or".to_string(), 1),
-            ("and".to_string(), 2),
-            ("==".to_string(), 3), ("!=".to_string(), 3), ("<".to_string(), 3), ("<=".to_string(), 3), (">".to_string(), 3), (">=".to_string(), 3),
-            ("+".to_string(), 4), ("-".to_string(), 4),
-            ("*".to_string(), 5), ("/".to_string(), 5),
-            (".".to_string(), 6),
-        ])
-});
-
-pub struct Tokenizer {}
+/// A single token and the column (0-based) it starts at
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub value: String,
+    pub column: usize,
+}
 
-impl Tokenizer {
-    pub fn new() -> Self {
-        Self {}
+impl Token {
+    // Column right after the token
+    pub fn end(&self) -> usize {
+        self.column + self.value.chars().count()
     }
-    // Tokenize an input string into a vector of Values (tokens), using regex and a version of the shunting yard algorithm
-    pub fn tokenize(
-        &self,
-        input: &str,
-        variables: &HashMap<String, Value>,
-        functions: &HashMap<String, HashMap<String, Value>>,
-        classes: &HashMap<String, Class>,
-        ipl_libraries: &HashMap<String, IPL_Library>,
-    ) -> Vec<Value> {
-        // println!("Tokenizing input: {}, variables: {:#?}", input, variables);
-        let tokens = self.split(input);
-        // println!("tokens after splitting: {:?}", tokens);
 
-        self.shunting_yard(tokens, variables, functions, classes, ipl_libraries)
+    pub fn is_string(&self) -> bool {
+        let quoted = self.value.trim_start_matches(['f', 'F']);
+        quoted.starts_with('"') || quoted.starts_with('\'')
     }
-    // Split the input string into tokens using regex
-    fn split(&self, input: &str) -> Vec<String> {
-        let tokens: Vec<String> = TOKEN_PATTERN
-            .find_iter(input)
-            .map(|mat| mat.as_str().to_string())
-            .collect();
-        tokens
+
+    // An f-string like f"Hello {name}"
+    pub fn is_format_string(&self) -> bool {
+        self.is_string() && self.value.starts_with(['f', 'F'])
     }
 
-    fn str_to_datatype(&self, token: &str) -> Value {
-        if token.starts_with('"') && token.ends_with('"')
-            || token.starts_with("'") && token.ends_with("'")
-        {
-            Value::Str(token.to_string())
-        } else if let Ok(num) = token.parse::<f64>(){
-            Value::Number(num)
+    // Length of the f prefix and the opening quotes
+    fn quote_length(&self) -> usize {
+        let prefix = usize::from(self.is_format_string());
+        let quoted = &self.value[prefix..];
+        if quoted.starts_with("\"\"\"") || quoted.starts_with("'''") {
+            prefix + 3
         } else {
-            Value::None
+            prefix + 1
         }
     }
 
-    // Convert infix tokens to postfix using the shunting yard algorithm
-    fn shunting_yard(
-        &self,
-        tokens: Vec<String>,
-        variables: &HashMap<String, Value>,
-        functions: &HashMap<String, HashMap<String, Value>>,
-        classes: &HashMap<String, Class>,
-        ipl_libraries: &HashMap<String, IPL_Library>,
-    ) -> Vec<Value> {
-        let mut output: Vec<Value> = vec![];
-        let mut stack: Vec<Value> = vec![];
+    // The text between the quotes of a string literal
+    pub fn text(&self) -> &str {
+        let closing = self.quote_length() - usize::from(self.is_format_string());
+        &self.value[self.quote_length()..self.value.len() - closing]
+    }
 
-        let mut i = 0;
-        while i < tokens.len() {
-            let token: &String = &tokens[i];
-            // println!("Shunting yard at {}", token);
-            let token_as_datatype = self.str_to_datatype(token);
-            if !token_as_datatype.is_none_value() {
-                output.push(token_as_datatype);
-            } else if i + 1 < tokens.len()
-                && (
-                    // variable or class in current scope
-                    (variables.contains_key(token)
-                        || classes.contains_key(token))
-                        && tokens[i + 1] == "."
-                    
-                    // or any library
-                    || ipl_libraries.contains_key(token)
-                    
-                    // OR variable in any library
-                    || ipl_libraries.values().any(|lib| lib.variables.contains_key(token))
-                    
-                    // OR class in any library followed by "."
-                    || (ipl_libraries.values().any(|lib| lib.classes.contains_key(token))
-                        && tokens[i + 1] == ".")
-                )
-            {
-                output.push(Value::Str(token.clone()));
+    // Column where the text between the quotes starts
+    pub fn text_column(&self) -> usize {
+        self.column + self.quote_length()
+    }
 
-                if &tokens[i + 1] == "." {
-                    i += 2; // Skip the '.' token
-                    if let Some(attr) = tokens.get(i) {
-                        output.push(Value::Str(".".to_string()));
-                        if !classes
-                            .values()
-                            .any(|class| class.functions.contains_key(attr))
-                            && !ipl_libraries
-                                .values()
-                                .any(|lib| lib.functions.contains_key(attr))
-                        {
-                            output.push(Value::Str(attr.clone()));
-                        } else {
-                            i -= 1; // Step back to reprocess this token as a function
-                        }
-                    } else {
-                        EvaluatioError::new("Expected attribute after '.'".to_string()).raise();
-                    }
-                }
-            } else if variables.contains_key(token) || ipl_libraries.contains_key(token) {
-                output.push(Value::Str(token.clone()));
-            } else if functions.contains_key(token)
-                || BUILT_IN_FUNCTIONS.contains_key(token as &str)
-                || classes.contains_key(token)
-                || classes
-                    .values()
-                    .any(|class| class.functions.contains_key(token))
-                || ipl_libraries
-                    .values()
-                    .any(|library| library.functions.contains_key(token))
-            {
-                if tokens.get(i + 1) != Some(&"(".to_string()) {
-                    EvaluatioError::new(format!("Function {} must be followed by (", token))
-                        .raise();
-                }
-                output.push(Value::Str(token.clone()));
-                i += 1; // Skip the next token which is '('
-                let mut function_arguments = vec![];
-                let mut argument: String = String::new();
-                while let Some(next_token) = tokens.get(i + 1) {
-                    if next_token == ")" {
-                        i += 1;
-                        break;
-                    } else if next_token == "," {
-                        if argument.is_empty() {
-                            i += 1;
-                            continue;
-                        }
-                        // println!("Pushing argument: {}", argument);
-                        function_arguments.push(Value::Str(argument.clone()));
-                        argument.clear();
-                        i += 1;
-                    } else {
-                        argument += next_token;
-                        i += 1;
-                    }
-                }
-                function_arguments.push(Value::Str(argument.clone()));
-                output.push(Value::List(function_arguments));
-            } else if PREC.contains_key(token) {
-                while let Some(last) = stack.last() {
-                    if PREC.contains_key(&last.to_string_value())
-                        && PREC[&last.to_string_value()] >= PREC[token]
-                    {
-                        output.push(stack.pop().unwrap());
-                    } else {
-                        break;
-                    }
-                }
-                stack.push(Value::Str(token.clone()));
-            } else if token == "[" {
-                if let Some(last_value) = output.last() && last_value.is_string() {
-                    // println!("Processing indexing for value: {:?}", last_value);
-                    let mut index_string = "".to_string();
-                    while let Some(next_token) = tokens.get(i + 1) {
-                        if next_token == "]" {
-                            i += 1;
-                            break;
-                        } else {
-                            // Build the index string
-                            index_string += next_token;
-                            i += 1;
-                        }
-                    }
-                    let value_at_index = self.get_index(last_value, index_string);
-                    output.push(value_at_index);
-                    i += 1;
-                    continue;
-                }
+    // The text of a string literal with escape sequences replaced
+    pub fn string_value(&self) -> IplResult<String> {
+        self.unescape(self.text(), 0)
+    }
 
-                let mut list_elements = vec![];
-                let mut element: String = "".to_string();
-                while let Some(next_token) = tokens.get(i + 1) {
-                    if next_token == "]" {
-                        i += 1;
-                        break;
-                    } else if next_token == "," {
-                        if element.is_empty() {
-                            i += 1;
-                            continue;
-                        }
-                        // println!("Pushing element: {}", element);
-                        list_elements.push(self.str_to_datatype(&element));
-                        element.clear();
-                        i += 1;
-                    } else {
-                        element += next_token;
-                        i += 1;
-                    }
-                }
-                list_elements.push(self.str_to_datatype(&element)); // push the last element
-                output.push(Value::List(list_elements));
-            } else if token == "(" {
-                stack.push(Value::Str(token.clone()));
-            } else if token == "," {
-                // Pop operators until we reach a left parenthesis
-                while let Some(last) = stack.last() {
-                    if last.to_string_value() != "(" {
-                        output.push(stack.pop().unwrap());
-                    } else {
-                        break;
-                    }
-                }
-                // Add the comma to the output
-                output.push(Value::Str(token.clone()));
-            } else if token == ")" {
-                while let Some(last) = stack.last() {
-                    if last.to_string_value() != "(" {
-                        output.push(stack.pop().unwrap());
-                    } else {
-                        break;
-                    }
+    // Replace the escape sequences in a part of the text, which starts at the given char offset
+    pub fn unescape(&self, text: &str, offset: usize) -> IplResult<String> {
+        let mut result = String::new();
+        let mut chars = text.chars().enumerate();
+        while let Some((index, c)) = chars.next() {
+            if c != '\\' {
+                result.push(c);
+                continue;
+            }
+            let escaped = match chars.next() {
+                Some((_, 'n')) => Some('\n'),
+                Some((_, 't')) => Some('\t'),
+                Some((_, 'r')) => Some('\r'),
+                Some((_, '0')) => Some('\0'),
+                Some((_, '\\')) => Some('\\'),
+                Some((_, '"')) => Some('"'),
+                Some((_, '\'')) => Some('\''),
+                // A backslash at the end of a line joins it with the next one
+                Some((_, '\n')) => None,
+                Some((_, letter @ ('u' | 'U'))) => {
+                    Some(self.unicode_escape(&mut chars, offset + index, letter)?)
                 }
-                if stack.is_empty() {
-                    EvaluatioError::new("Mismatched parentheses".to_string()).raise();
+                other => {
+                    let sequence: String = other.map(|(_, c)| c).into_iter().collect();
+                    return self.escape_error(
+                        offset + index,
+                        format!("Unknown escape sequence \\{}", sequence),
+                    );
                 }
-                stack.pop(); // remove "("
-            } else {
-                EvaluatioError::new(format!("Unknown token {}", token)).raise();
-            }
-            i += 1;
+            };
+            result.extend(escaped);
         }
+        Ok(result)
+    }
 
-        //println!("Stack after processing tokens: {:?}", stack);
-        //println!("Output after processing tokens: {:?}", output);
-        while let Some(last) = stack.last() {
-            if last.to_string_value() == "(" || last.to_string_value() == ")" {
-                EvaluatioError::new("Mismatched parentheses".to_string()).raise();
-            }
-            output.push(stack.pop().unwrap());
+    // Read the hex digits of \uXXXX or \UXXXXXXXX
+    fn unicode_escape(
+        &self,
+        chars: &mut impl Iterator<Item = (usize, char)>,
+        index: usize,
+        letter: char,
+    ) -> IplResult<char> {
+        let digits = if letter == 'u' { 4 } else { 8 };
+        let hex: String = chars.take(digits).map(|(_, c)| c).collect();
+        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+            Some(c) if hex.len() == digits => Ok(c),
+            _ => self.escape_error(
+                index,
+                format!("Invalid unicode escape \\{}{}", letter, hex),
+            ),
         }
+    }
 
-        output
+    fn escape_error<T>(&self, index: usize, message: String) -> IplResult<T> {
+        let column = self.column + self.quote_length() + index;
+        EvaluatioError::new(ErrorKind::Syntax, message)
+            .at(column, column + 2)
+            .raise()
     }
-    fn get_index(&self, list: &Value, index_string: String) -> Value {
-        // println!("Getting index '{}' from list {:?}", index_string, list);
-        if index_string.contains(":") {
-            // List index
-            let parts: Vec<&str> = index_string.split(':').collect();
-            // println!("Parts: {:?}", parts);
-            let start: usize = if parts[0].is_empty() {
-                0
-            } else {
-                parts[0].trim().parse().unwrap_or(0)
-            };
-            let end: usize = if parts.len() > 1 && !parts[1].is_empty() {
-                parts[1].trim().parse().unwrap_or(0)
-            } else {
-                list.length()
-            };
+}
 
-            if start > end {
-                EvaluatioError::new("Start index cannot be greater than end index".to_string())
-                    .raise();
-            }
+// Check if the source ends inside a triple-quoted string, so it continues on the next line
+pub fn opens_multiline_string(source: &str) -> bool {
+    scan_strings(source).1
+}
+
+// Remove the comment from a line, a # inside a string literal does not start one
+pub fn strip_comment(line: &str) -> &str {
+    match scan_strings(line).0 {
+        Some(start) => &line[..start],
+        None => line,
+    }
+}
 
-            Value::IndexValue(IndexValue {
-                start,
-                end,
-            })
+// Walk over the source, skipping string literals and comments
+// Returns where the first comment starts, and if the source ends inside a triple-quoted string
+fn scan_strings(source: &str) -> (Option<usize>, bool) {
+    let mut first_comment = None;
+    let mut position = 0;
+    while let Some(found) = source[position..].find(['"', '\'', '#']) {
+        let start = position + found;
+        let rest = &source[start..];
+        if rest.starts_with('#') {
+            first_comment.get_or_insert(start);
+            // Skip the comment up to the end of its line
+            match rest.find('\n') {
+                Some(end) => position = start + end,
+                None => return (first_comment, false),
+            }
+            continue;
+        }
+        let quote = if rest.starts_with("\"\"\"") {
+            "\"\"\""
+        } else if rest.starts_with("'''") {
+            "'''"
         } else {
-            // Single index
-            let index: usize = index_string.trim().parse().unwrap_or(0);
-            Value::IndexValue(IndexValue {
-                start: index,
-                end: index,
-            })
+            &rest[..1]
+        };
+        let text_start = start + quote.len();
+        match find_closing_quote(&source[text_start..], quote) {
+            Some(end) => position = text_start + end + quote.len(),
+            None => return (first_comment, quote.len() == 3),
+        }
+    }
+    (first_comment, false)
+}
+
+fn find_closing_quote(text: &str, quote: &str) -> Option<usize> {
+    let mut chars = text.char_indices();
+    while let Some((index, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if text[index..].starts_with(quote) {
+            return Some(index);
+        }
+    }
+    None
+}
+
+pub struct Tokenizer {}
+
+impl Tokenizer {
+    pub fn new() -> Self {
+        Self {}
+    }
+    // Split a line into tokens using regex, raising an error on anything the pattern doesnt know
+    pub fn tokenize(&self, input: &str) -> IplResult<Vec<Token>> {
+        let mut tokens: Vec<Token> = vec![];
+        let mut last_end = 0;
+        for mat in TOKEN_PATTERN.find_iter(input) {
+            self.check_skipped(input, last_end, mat.start())?;
+            tokens.push(Token {
+                value: mat.as_str().to_string(),
+                column: input[..mat.start()].chars().count(),
+            });
+            last_end = mat.end();
+        }
+        self.check_skipped(input, last_end, input.len())?;
+        // println!("tokens after splitting: {:?}", tokens);
+        Ok(tokens)
+    }
+
+    // Check that only whitespace was skipped between two tokens
+    fn check_skipped(&self, input: &str, start: usize, end: usize) -> IplResult<()> {
+        let skipped = &input[start..end];
+        if !skipped.trim().is_empty() {
+            let offset = skipped.len() - skipped.trim_start().len();
+            let column = input[..start + offset].chars().count();
+            let unknown = skipped.trim();
+            return EvaluatioError::new(ErrorKind::Syntax, format!("Unknown token {}", unknown))
+                .at(column, column + unknown.chars().count())
+                .raise();
         }
+        Ok(())
     }
 }
@@ -1,67 +1,167 @@
 use std::cmp::Ordering;
-use std::ops::Index;
-use std::ops::{Add, Div, Mul, Sub};
+use std::ops::{Add, Div, Mul, Rem, Sub};
 use std::path::PathBuf;
 use std::collections::HashMap;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use crate::ast::Stmt;
 
 // Define Class, Instance, and Value types for the evaluator
 
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub(crate) name: String,
+    pub(crate) file: PathBuf,
+    pub(crate) arguments: Vec<String>,
+    pub(crate) defaults: HashMap<String, Value>, // Values of the parameters that can be left out
+    pub(crate) body: Rc<Vec<Stmt>>,
+    pub(crate) closure: Option<Rc<Environment>>, // Variables of the function it was defined in
+    pub(crate) lib_name: String, // Library the function was defined in, empty for the main program
+}
+
+/// Local variables of a running function, shared with the functions defined inside it
+#[derive(Debug, Default)]
+pub struct Environment {
+    pub(crate) variables: RefCell<HashMap<String, Value>>,
+    pub(crate) parent: Option<Rc<Environment>>, // The environment the function was defined in
+}
+
+impl Environment {
+    pub(crate) fn new(variables: HashMap<String, Value>, parent: Option<Rc<Environment>>) -> Self {
+        Self {
+            variables: RefCell::new(variables),
+            parent,
+        }
+    }
+
+    // Look up a variable here or in the enclosing functions
+    pub(crate) fn get(&self, name: &str) -> Option<Value> {
+        if let Some(value) = self.variables.borrow().get(name) {
+            return Some(value.clone());
+        }
+        self.parent.as_ref()?.get(name)
+    }
+
+    // Drop the variables of a finished call, unless a function defined in it is still used.
+    // Functions stored in its own variables point back at it, so it would never be freed
+    pub(crate) fn release(self: &Rc<Self>) {
+        let inner_references: usize = self
+            .variables
+            .borrow()
+            .values()
+            .map(|value| value.closure_references(self))
+            .sum();
+        // The running call holds the only other reference
+        if Rc::strong_count(self) == inner_references + 1 {
+            let variables = std::mem::take(&mut *self.variables.borrow_mut());
+            drop(variables);
+        }
+    }
+
+    // This or the closest enclosing environment that has the variable
+    pub(crate) fn holding(self: &Rc<Self>, name: &str) -> Option<Rc<Environment>> {
+        if self.variables.borrow().contains_key(name) {
+            Some(Rc::clone(self))
+        } else {
+            self.find_enclosing(name)
+        }
+    }
+
+    // The closest enclosing environment that has the variable, for nonlocal
+    pub(crate) fn find_enclosing(&self, name: &str) -> Option<Rc<Environment>> {
+        let parent = self.parent.as_ref()?;
+        if parent.variables.borrow().contains_key(name) {
+            Some(Rc::clone(parent))
+        } else {
+            parent.find_enclosing(name)
+        }
+    }
+}
+
+/// Something that can be called: a function, a lambda, a method or a built-in function
+#[derive(Debug, Clone)]
+pub enum Callable {
+    Function(Function), // Defined with def or lambda
+    Method(Rc<RefCell<Instance>>, String), // Bound to its instance
+    ClassFunction(ClassStr, String),       // Called on the class itself
+    LibraryFunction(String, String),       // Library name and function name
+    BuiltIn(String),                       // Built-in or registered native function
+}
+
+impl Callable {
+    pub fn name(&self) -> &str {
+        match self {
+            Callable::Function(function) => &function.name,
+            Callable::Method(_, name)
+            | Callable::ClassFunction(_, name)
+            | Callable::LibraryFunction(_, name)
+            | Callable::BuiltIn(name) => name,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Class {
-    pub functions: HashMap<String, HashMap<String, Value>>,
-    pub variables: HashMap<String, Value>,
+    pub(crate) functions: HashMap<String, Function>,
+    pub(crate) variables: HashMap<String, Value>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Instance {
-    pub class: ClassStr,
-    pub variables: HashMap<String, Value>,
+    pub(crate) class: ClassStr,
+    pub(crate) variables: HashMap<String, Value>,
 }
 
 #[allow(non_camel_case_types)] // For readability
 #[derive(Debug, Clone)]
 pub struct IPL_Library {
-    pub lib_name: String,
-    pub variables: HashMap<String, Value>,
-    pub functions: HashMap<String, HashMap<String, Value>>,
-    pub classes: HashMap<String, Class>,
+    pub(crate) lib_name: String,
+    pub(crate) variables: HashMap<String, Value>,
+    pub(crate) functions: HashMap<String, Function>,
+    pub(crate) classes: HashMap<String, Class>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ClassStr {
-    pub class_name: String,
-    pub lib_name: String,
-}
-
-#[derive(Debug, Clone)]
-pub struct IndexValue {
-    pub start: usize,
-    pub end: usize,
+    pub(crate) class_name: String,
+    pub(crate) lib_name: String,
 }
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    Number(f64),
+    Int(i64),
+    Number(f64), // Floating point
     List(Vec<Value>),
+    Map(Vec<(Value, Value)>), // Key value pairs in insertion order
     Bool(bool),
     Str(String),
     Path(Box<PathBuf>),
-    Instance(Box<Instance>),
+    Instance(Rc<RefCell<Instance>>), // Shared, so every reference sees changes
     #[allow(non_camel_case_types)] // For readability
     IPL_Library(Box<IPL_Library>),
     ClassStr(Box<ClassStr>),
-    IndexValue(IndexValue),
+    Function(Box<Callable>),
     None,
 }
 
-impl Index<usize> for Value {
-    type Output = Value;
-
-    fn index(&self, idx: usize) -> &Self::Output {
-        match self {
-            Value::List(vec) => &vec[idx],
-            _ => panic!("Indexing only supported on Value::List"),
-        }
+// Apply an arithmetic operator to two numbers. Two ints give an exact int,
+// or None if the result does not fit in one. Any float involved gives a float
+fn arithmetic(
+    lhs: Value,
+    rhs: Value,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> Value {
+    match (lhs, rhs) {
+        (Value::Int(a), Value::Int(b)) => match int_op(a, b) {
+            Some(result) => Value::Int(result),
+            None => Value::None,
+        },
+        (lhs, rhs) => match (lhs.as_number(), rhs.as_number()) {
+            (Some(a), Some(b)) => Value::Number(float_op(a, b)),
+            _ => Value::None,
+        },
     }
 }
 
@@ -70,9 +170,12 @@ impl Add for Value {
 
     fn add(self, rhs: Value) -> Value {
         match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
             (Value::Str(a), Value::Str(b)) => Value::Str(a + &b),
-            _ => Value::None,
+            (Value::List(mut a), Value::List(b)) => {
+                a.extend(b);
+                Value::List(a)
+            }
+            (a, b) => arithmetic(a, b, i64::checked_add, |a, b| a + b),
         }
     }
 }
@@ -81,10 +184,7 @@ impl Sub for Value {
     type Output = Value;
 
     fn sub(self, rhs: Value) -> Value {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a - b),
-            _ => Value::None,
-        }
+        arithmetic(self, rhs, i64::checked_sub, |a, b| a - b)
     }
 }
 
@@ -92,32 +192,135 @@ impl Mul for Value {
     type Output = Value;
 
     fn mul(self, rhs: Value) -> Value {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a * b),
-            _ => Value::None,
-        }
+        arithmetic(self, rhs, i64::checked_mul, |a, b| a * b)
     }
 }
 
+// True division, the result is always a float
 impl Div for Value {
     type Output = Value;
 
     fn div(self, rhs: Value) -> Value {
-        match (self, rhs) {
-            (Value::Number(_), Value::Number(0.0)) => Value::None,
-            (Value::Number(a), Value::Number(b)) => Value::Number(a / b),
+        match (self.as_number(), rhs.as_number()) {
+            (Some(_), Some(0.0)) => Value::None,
+            (Some(a), Some(b)) => Value::Number(a / b),
             _ => Value::None,
         }
     }
 }
 
+// Remainder with the sign of the divisor, like Python: -7 % 3 is 2
+impl Rem for Value {
+    type Output = Value;
+
+    fn rem(self, rhs: Value) -> Value {
+        if rhs.as_number() == Some(0.0) {
+            return Value::None;
+        }
+        arithmetic(self, rhs, int_remainder, float_remainder)
+    }
+}
+
+fn float_remainder(a: f64, b: f64) -> f64 {
+    a - b * (a / b).floor()
+}
+
+fn int_remainder(a: i64, b: i64) -> Option<i64> {
+    // Only i64::MIN % -1 wraps, and its remainder 0 is right
+    let remainder = a.wrapping_rem(b);
+    if remainder != 0 && (remainder < 0) != (b < 0) {
+        Some(remainder + b)
+    } else {
+        Some(remainder)
+    }
+}
+
+// Read the digits at the position of a format spec
+fn read_number(chars: &[char], position: &mut usize) -> Option<usize> {
+    let digits: String = chars[*position..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    *position += digits.len();
+    digits.parse().ok()
+}
+
+// Conversions from Rust values, mainly for embedding IPL
+impl From<f64> for Value {
+    fn from(number: f64) -> Self {
+        Value::Number(number)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(number: i64) -> Self {
+        Value::Int(number)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(boolean: bool) -> Self {
+        Value::Bool(boolean)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(string: &str) -> Self {
+        Value::Str(string.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(string: String) -> Self {
+        Value::Str(string)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(list: Vec<Value>) -> Self {
+        Value::List(list)
+    }
+}
+
+// Compare an int with a float exactly, converting the int to a float could round it
+fn compare_int_float(int: i64, float: f64) -> Option<Ordering> {
+    // 2^63, the first float above every int
+    const INT_END: f64 = 9_223_372_036_854_775_808.0;
+    if float.is_nan() {
+        return None;
+    }
+    if float >= INT_END {
+        return Some(Ordering::Less);
+    }
+    if float < -INT_END {
+        return Some(Ordering::Greater);
+    }
+    // The whole part fits into an int, the fraction decides between equal ints
+    let whole = float.trunc();
+    match int.cmp(&(whole as i64)) {
+        Ordering::Equal => 0.0.partial_cmp(&(float - whole)),
+        ordering => Some(ordering),
+    }
+}
+
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
             (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Int(a), Value::Number(b)) | (Value::Number(b), Value::Int(a)) => {
+                compare_int_float(*a, *b) == Some(Ordering::Equal)
+            }
             (Value::Str(a), Value::Str(b)) => a == b,
             (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a == b,
+            // Maps are equal with the same entries, in any order
+            (Value::Map(a), Value::Map(b)) => {
+                a.len() == b.len()
+                    && a.iter().all(|(key, value)| other.get_entry(key) == Some(value))
+            }
             (Value::None, Value::None) => true,
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
@@ -126,7 +329,10 @@ impl PartialEq for Value {
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
             (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Number(b)) => compare_int_float(*a, *b),
+            (Value::Number(a), Value::Int(b)) => compare_int_float(*b, *a).map(Ordering::reverse),
             (Value::Str(a), Value::Str(b)) => a.partial_cmp(b),
             (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
             _ => None,
@@ -138,36 +344,28 @@ impl PartialOrd for Value {
 impl Value {
     pub fn as_f64(&self) -> f64 {
         match self {
+            Value::Int(n) => *n as f64,
             Value::Number(n) => *n,
-            Value::Bool(b) => {
-                if *b {
-                    1.0
-                } else {
-                    0.0
-                }
-            }
+            Value::Bool(b) => u8::from(*b).into(),
             Value::Str(s) => s.parse::<f64>().unwrap_or(0.0),
             _ => 0.0,
         }
     }
     pub fn as_usize(&self) -> usize {
         match self {
+            Value::Int(n) => *n as usize,
             Value::Number(n) => *n as usize,
-            Value::Bool(b) => {
-                if *b {
-                    1
-                } else {
-                    0
-                }
-            }
+            Value::Bool(b) => usize::from(*b),
             _ => 0,
         }
     }
     pub fn as_bool(&self) -> bool {
         match self {
             Value::Bool(b) => *b,
+            Value::Int(n) => *n != 0,
             Value::Number(n) => *n != 0.0,
             Value::Str(s) => !s.is_empty(),
+            Value::Map(m) => !m.is_empty(),
             Value::None => false,
             _ => true,
         }
@@ -178,8 +376,19 @@ impl Value {
             _ => None,
         }
     }
+    // Int or float as f64, None for other types
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Int(n) => Some(*n as f64),
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
     pub fn to_string_value(&self) -> String {
         match self {
+            Value::Int(n) => n.to_string(),
+            // Whole floats keep their .0 so they can be told apart from ints
+            Value::Number(n) if n.is_finite() && n.fract() == 0.0 => format!("{:.1}", n),
             Value::Number(n) => n.to_string(),
             Value::Bool(b) => b.to_string(),
             Value::Str(s) => s.to_string(),
@@ -189,25 +398,220 @@ impl Value {
                 let elements: Vec<String> = v.iter().map(|val| val.to_string_value()).collect();
                 format!("[{}]", elements.join(", "))
             }
+            Value::Map(m) => {
+                let entries: Vec<String> = m
+                    .iter()
+                    .map(|(key, value)| {
+                        format!("{}: {}", key.to_string_value(), value.to_string_value())
+                    })
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Value::Function(callable) => format!("<function {}>", callable.name()),
             _ => "".to_string(),
         }
     }
+    // Format a value for an f-string field with a spec like ".2f", ">10" or "05",
+    // None if the spec is invalid or does not fit the type of the value
+    pub fn format(&self, spec: &str) -> Option<String> {
+        let chars: Vec<char> = spec.chars().collect();
+        let is_align = |c: Option<&char>| matches!(c, Some('<' | '>' | '^'));
+        let (mut fill, mut align, mut position) = (' ', None, 0);
+        if is_align(chars.get(1)) {
+            (fill, align, position) = (chars[0], Some(chars[1]), 2);
+        } else if is_align(chars.first()) {
+            (align, position) = (Some(chars[0]), 1);
+        }
+        let zero_padded = chars.get(position) == Some(&'0');
+        if zero_padded {
+            position += 1;
+        }
+        let width = read_number(&chars, &mut position).unwrap_or(0);
+        let precision = if chars.get(position) == Some(&'.') {
+            position += 1;
+            Some(read_number(&chars, &mut position)?)
+        } else {
+            None
+        };
+        let kind = chars.get(position).copied();
+        if chars.len() > position + 1 {
+            return None;
+        }
+
+        let text = match (kind, self) {
+            (Some('d') | None, Value::Int(n)) if precision.is_none() => n.to_string(),
+            (Some('f') | None, Value::Int(_) | Value::Number(_)) => match (kind, precision) {
+                (None, None) => self.to_string_value(),
+                (_, precision) => format!("{:.*}", precision.unwrap_or(6), self.as_f64()),
+            },
+            (Some('s') | None, _) => match precision {
+                Some(precision) => self.to_string_value().chars().take(precision).collect(),
+                None => self.to_string_value(),
+            },
+            _ => return None,
+        };
+
+        let padding = width.saturating_sub(text.chars().count());
+        if zero_padded && align.is_none() {
+            if self.is_number() {
+                // The zeros go between the sign and the digits, like -005
+                let (sign, digits) = match text.strip_prefix('-') {
+                    Some(digits) => ("-", digits),
+                    None => ("", text.as_str()),
+                };
+                return Some(format!("{}{}{}", sign, "0".repeat(padding), digits));
+            }
+            fill = '0';
+        }
+        // Numbers are aligned right and everything else left by default
+        let default_align = if self.is_number() { '>' } else { '<' };
+        let fill = fill.to_string();
+        Some(match align.unwrap_or(default_align) {
+            '<' => text + &fill.repeat(padding),
+            '>' => fill.repeat(padding) + &text,
+            _ => fill.repeat(padding / 2) + &text + &fill.repeat(padding - padding / 2),
+        })
+    }
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Number(_) => "float",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Bool(_) => "bool",
+            Value::Str(_) => "string",
+            Value::Path(_) => "path",
+            Value::Instance(_) => "instance",
+            Value::IPL_Library(_) => "library",
+            Value::ClassStr(_) => "class",
+            Value::Function(_) => "function",
+            Value::None => "none",
+        }
+    }
+    // The type name for messages, like "an int" or "a list"
+    pub fn type_name_with_article(&self) -> String {
+        let name = self.type_name();
+        let article = if name.starts_with(['a', 'e', 'i', 'o', 'u']) { "an" } else { "a" };
+        format!("{} {}", article, name)
+    }
     pub fn length(&self) -> usize {
         match self {
             Value::List(v) => v.len(),
-            Value::Str(s) => s.len(),
+            Value::Map(m) => m.len(),
+            Value::Str(s) => s.chars().count(),
             _ => 0,
         }
     }
-    pub fn iter(&self) -> Box<dyn Iterator<Item = &Value> + '_> {
+    // The elements a for loop goes through: items of a list, keys of a map
+    // or characters of a string. None if the value cannot be iterated
+    pub fn iter(&self) -> Option<Box<dyn Iterator<Item = Value> + '_>> {
+        match self {
+            Value::List(v) => Some(Box::new(v.iter().cloned())),
+            Value::Map(m) => Some(Box::new(m.iter().map(|(key, _)| key.clone()))),
+            Value::Str(s) => Some(Box::new(s.chars().map(|c| Value::Str(c.to_string())))),
+            _ => None,
+        }
+    }
+    // Look up the value for a key of a map
+    pub fn get_entry(&self, key: &Value) -> Option<&Value> {
+        match self {
+            Value::Map(m) => m.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+    // Look up the value for a key of a map to change it
+    pub fn get_entry_mut(&mut self, key: &Value) -> Option<&mut Value> {
+        match self {
+            Value::Map(m) => m.iter_mut().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+    // Set the value for a key of a map, adding the key if it is new
+    pub fn set_entry(&mut self, key: Value, value: Value) {
+        if let Value::Map(m) = self {
+            match m.iter_mut().find(|(k, _)| *k == key) {
+                Some(entry) => entry.1 = value,
+                None => m.push((key, value)),
+            }
+        }
+    }
+    // Check if a collection contains an item, None if the types do not support it
+    pub fn contains(&self, item: &Value) -> Option<bool> {
+        match (self, item) {
+            (Value::List(v), _) => Some(v.contains(item)),
+            (Value::Map(_), _) => Some(self.get_entry(item).is_some()),
+            (Value::Str(s), Value::Str(part)) => Some(s.contains(part.as_str())),
+            _ => None,
+        }
+    }
+    // The // operator, rounding the quotient down like Python
+    pub fn floor_div(self, rhs: Value) -> Value {
+        if rhs.as_number() == Some(0.0) {
+            return Value::None;
+        }
+        arithmetic(
+            self,
+            rhs,
+            |a, b| {
+                let quotient = a.checked_div(b)?;
+                if a % b != 0 && (a < 0) != (b < 0) {
+                    Some(quotient - 1)
+                } else {
+                    Some(quotient)
+                }
+            },
+            |a, b| (a / b).floor(),
+        )
+    }
+    // The ** operator, an int to a negative power is a float
+    pub fn pow(self, rhs: Value) -> Value {
+        if let (Value::Int(a), Value::Int(b)) = (&self, &rhs)
+            && *b < 0
+        {
+            return Value::Number((*a as f64).powf(*b as f64));
+        }
+        arithmetic(
+            self,
+            rhs,
+            |a, b| {
+                // Exponents this big overflow unless the base is 0, 1 or -1,
+                // for those only whether the exponent is odd matters
+                let exponent = u32::try_from(b).unwrap_or(u32::MAX - u32::from(b % 2 == 0));
+                a.checked_pow(exponent)
+            },
+            f64::powf,
+        )
+    }
+    // How often functions in this value hold the environment as their closure.
+    // Instances are not searched, they can be shared with code outside the environment
+    fn closure_references(&self, environment: &Rc<Environment>) -> usize {
         match self {
-            Value::List(v) => Box::new(v.iter()),
-            _ => Box::new(std::iter::empty()),
+            Value::Function(callable) => match callable.as_ref() {
+                Callable::Function(function) => {
+                    let own = function
+                        .closure
+                        .as_ref()
+                        .is_some_and(|closure| Rc::ptr_eq(closure, environment));
+                    let defaults: usize = function
+                        .defaults
+                        .values()
+                        .map(|value| value.closure_references(environment))
+                        .sum();
+                    usize::from(own) + defaults
+                }
+                _ => 0,
+            },
+            Value::List(list) => list.iter().map(|value| value.closure_references(environment)).sum(),
+            Value::Map(map) => map
+                .iter()
+                .map(|(_, value)| value.closure_references(environment))
+                .sum(),
+            _ => 0,
         }
     }
-    pub fn get_instance(&self) -> Option<Instance> {
+    pub fn get_instance(&self) -> Option<Rc<RefCell<Instance>>> {
         match self {
-            Value::Instance(inst) => Some(inst.as_ref().clone()),
+            Value::Instance(inst) => Some(Rc::clone(inst)),
             _ => None,
         }
     }
@@ -215,7 +619,7 @@ impl Value {
         matches!(self, Value::None)
     }
     pub fn is_number(&self) -> bool {
-        matches!(self, Value::Number(_))
+        matches!(self, Value::Int(_) | Value::Number(_))
     }
     pub fn is_string(&self) -> bool {
         matches!(self, Value::Str(_))
@@ -226,6 +630,9 @@ impl Value {
     pub fn is_list(&self) -> bool {
         matches!(self, Value::List(_))
     }
+    pub fn is_map(&self) -> bool {
+        matches!(self, Value::Map(_))
+    }
     pub fn is_instance(&self) -> bool {
         matches!(self, Value::Instance(_))
     }
@@ -1,17 +1,84 @@
-use std::process::Command;
+use std::cell::RefCell;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+use ipl::{EvaluatioError, Evaluator};
+
+// Run an IPL file in this process, capturing what it writes with out()
+fn ev_captured(file: &str) -> (String, Result<(), Box<EvaluatioError>>) {
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    let mut evaluator = Evaluator::new();
+    evaluator.set_output(buffer.clone());
+    let result = evaluator.ev_file(file);
+    let output = String::from_utf8_lossy(&buffer.borrow()).to_string();
+    (output, result)
+}
 
 pub fn run_ipl_file(file: &str) -> String {
-    let output = Command::new("cargo")
-        .args(["run", "--quiet", "--", file])
-        .output()
-        .expect("failed to run IPL file");
+    let (output, result) = ev_captured(file);
 
     println!("Output: {:#?}", output);
-    String::from_utf8_lossy(&output.stdout).to_string()
+    if let Err(error) = result {
+        println!("{}", error);
+    }
+    output
 }
 
+// Evaluate source code that is expected to fail, returning the error message
+pub fn source_error(source: &str) -> String {
+    let mut evaluator = Evaluator::new();
+    evaluator.set_output(Rc::new(RefCell::new(Vec::new())));
+    let error = evaluator.ev_source(source).expect_err("the source should fail");
+    error.to_string()
+}
 
 pub fn assert_lines(output: &str, expected: Vec<&str>) {
     let lines : Vec<&str> = output.lines().collect();
     assert_eq!(lines, expected);
-}
\ No newline at end of file
+}
+// Run an IPL file that is expected to fail, returning the output and the error message
+pub fn run_failing_ipl_file(file: &str) -> (String, String) {
+    let (output, result) = ev_captured(file);
+
+    println!("Output: {:#?}", output);
+    let error = result.expect_err("the IPL file should fail");
+    (output, error.to_string())
+}
+// Start the REPL and type the given input, returning stdout and stderr
+pub fn run_repl(input: &str) -> (String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_IPL"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start the REPL");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .expect("failed to write to the REPL");
+    let output = child.wait_with_output().expect("failed to run the REPL");
+
+    println!("Output: {:#?}", output);
+    assert_eq!(output.status.code(), Some(0));
+    (
+        String::from_utf8_lossy(&output.stdout).to_string(),
+        String::from_utf8_lossy(&output.stderr).to_string(),
+    )
+}
+// Run an IPL file with the binary, using the libraries in tests/ili instead of the installed ones
+pub fn run_ipl_file_with_test_libraries(file: &str) -> (String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_IPL"))
+        .arg(file)
+        .env("ILI_PATH", concat!(env!("CARGO_MANIFEST_DIR"), "/tests/ili"))
+        .output()
+        .expect("failed to run the IPL file");
+
+    println!("Output: {:#?}", output);
+    (
+        String::from_utf8_lossy(&output.stdout).to_string(),
+        String::from_utf8_lossy(&output.stderr).to_string(),
+    )
+}
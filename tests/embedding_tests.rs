@@ -0,0 +1,173 @@
+use std::cell::RefCell;
+use std::io::Cursor;
+use std::rc::Rc;
+use std::thread;
+
+use ipl::{ErrorKind, EvaluatioError, Evaluator, Value};
+
+#[test]
+fn test_ev_source_returns_last_expression() {
+    let mut evaluator = Evaluator::new();
+    assert_eq!(evaluator.ev_source("x = 4\nx * 2").unwrap(), Value::Int(8));
+    assert_eq!(evaluator.ev_source("y = 1").unwrap(), Value::None);
+}
+
+#[test]
+fn test_globals() {
+    let mut evaluator = Evaluator::new();
+    evaluator.set_variable("name", Value::from("IPL"));
+    evaluator.ev_source("greeting = \"Hello \" + name").unwrap();
+    assert_eq!(evaluator.get_variable("greeting"), Some(&Value::from("Hello IPL")));
+    assert!(evaluator.get_variable("missing").is_none());
+}
+
+#[test]
+fn test_call_ipl_function() {
+    let mut evaluator = Evaluator::new();
+    evaluator.ev_file("tests/ipl_files/functions.ipl").unwrap();
+    evaluator.ev_source("def double(n)\n    return n * 2").unwrap();
+    let result = evaluator.call("double", vec![Value::from(21.0)]).unwrap();
+    assert_eq!(result, Value::Number(42.0));
+    let result = evaluator.call("len", vec![Value::from("four")]).unwrap();
+    assert_eq!(result, Value::Int(4));
+}
+
+#[test]
+fn test_errors_are_returned() {
+    let mut evaluator = Evaluator::new();
+    let error = evaluator.ev_source("out(1)\nout(missing)").unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Name);
+    assert_eq!(error.line_number, 2);
+    assert_eq!(error.file, "<string>");
+
+    let error = evaluator.call("missing", vec![]).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Name);
+
+    evaluator.ev_source("def one(a)\n    return a").unwrap();
+    let error = evaluator.call("one", vec![]).unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Argument);
+}
+
+#[test]
+fn test_traceback_names_the_source() {
+    let mut evaluator = Evaluator::new();
+    evaluator.ev_source("def fails()\n    return missing").unwrap();
+    let error = evaluator.ev_source("fails()").unwrap_err();
+    assert_eq!(error.file, "<string>");
+    let files: Vec<&str> = error.traceback.iter().map(|frame| frame.file_path.as_str()).collect();
+    assert_eq!(files, vec!["<string>", "<string>"]);
+    assert!(error.to_string().contains("<string>:2 in fails: return missing"));
+}
+
+#[test]
+fn test_quit_returns_to_the_host() {
+    let mut evaluator = Evaluator::new();
+    let error = evaluator.ev_source("x = 1\nquit()\nx = 2").unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Quit);
+    assert_eq!(evaluator.get_variable("x"), Some(&Value::Int(1)));
+}
+
+#[test]
+fn test_evaluators_on_threads() {
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            thread::spawn(move || {
+                let mut evaluator = Evaluator::new();
+                evaluator.set_variable("i", Value::from(i as f64));
+                evaluator.ev_source("i + 1").unwrap().as_f64()
+            })
+        })
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join().unwrap(), i as f64 + 1.0);
+    }
+}
+
+#[test]
+fn test_native_functions() {
+    let mut evaluator = Evaluator::new();
+    evaluator.register_function("double", 1, |args| Ok(Value::from(args[0].as_f64() * 2.0)));
+    assert_eq!(evaluator.ev_source("double(4) + 1").unwrap(), Value::Number(9.0));
+
+    // Native functions can be used in methods and can keep state
+    let calls = Rc::new(RefCell::new(vec![]));
+    let log = Rc::clone(&calls);
+    evaluator.register_function("log", 1, move |args| {
+        log.borrow_mut().push(args[0].to_string_value());
+        Ok(Value::None)
+    });
+    let source = "class Greeter\n    def greet(name)\n        log(\"Hi \" + name)\n        return double(2)\ng = Greeter()\ng.greet(\"Ada\")";
+    assert_eq!(evaluator.ev_source(source).unwrap(), Value::Number(4.0));
+    assert_eq!(*calls.borrow(), vec!["Hi Ada"]);
+}
+
+#[test]
+fn test_native_function_errors() {
+    let mut evaluator = Evaluator::new();
+    evaluator.register_function("fail", 0, |_| {
+        EvaluatioError::new(ErrorKind::Runtime, "host failed".to_string()).raise()
+    });
+    let error = evaluator.ev_source("x = 1\nfail()").unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Runtime);
+    assert_eq!(error.message, "host failed");
+    assert_eq!(error.line_number, 2);
+
+    let error = evaluator.ev_source("fail(1)").unwrap_err();
+    assert_eq!(error.kind, ErrorKind::Argument);
+}
+
+#[test]
+fn test_scripted_input_and_captured_output() {
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    let mut evaluator = Evaluator::new();
+    evaluator.set_output(buffer.clone());
+    evaluator.set_input(Rc::new(RefCell::new(Cursor::new("Ada\n"))));
+    evaluator
+        .ev_source("name = in(\"Name?\")\nout(\"Hello \" + name)")
+        .unwrap();
+    let output = String::from_utf8(buffer.borrow().clone()).unwrap();
+    assert_eq!(output, "Name?\nHello Ada\n");
+}
+
+// Whether each instance passed to watch() is still alive
+type Watched = Rc<RefCell<Vec<Box<dyn Fn() -> bool>>>>;
+
+fn watch_instances(evaluator: &mut Evaluator) -> Watched {
+    let watched: Watched = Rc::new(RefCell::new(vec![]));
+    let list = Rc::clone(&watched);
+    evaluator.register_function("watch", 1, move |args| {
+        if let Value::Instance(instance) = &args[0] {
+            let weak = Rc::downgrade(instance);
+            list.borrow_mut().push(Box::new(move || weak.upgrade().is_some()));
+        }
+        Ok(Value::None)
+    });
+    watched
+}
+
+#[test]
+fn test_locals_of_calls_with_nested_functions_are_freed() {
+    let mut evaluator = Evaluator::new();
+    let watched = watch_instances(&mut evaluator);
+    let source = [
+        "class Thing",
+        "    self.x = 0",
+        "def local_closures()",
+        "    thing = Thing()",
+        "    watch(thing)",
+        "    def get()",
+        "        return thing",
+        "    others = [lambda: get()]",
+        "def escaping_closure()",
+        "    thing = Thing()",
+        "    watch(thing)",
+        "    return lambda: thing",
+        "local_closures()",
+        "kept = escaping_closure()",
+    ]
+    .join("\n");
+    evaluator.ev_source(&source).unwrap();
+    let alive: Vec<bool> = watched.borrow().iter().map(|is_alive| is_alive()).collect();
+    // Only the closure that was returned keeps its variables alive
+    assert_eq!(alive, vec![false, true]);
+}
@@ -1,13 +1,21 @@
 mod common;
 use std::vec;
 
-use common::{run_ipl_file, assert_lines};
+use ipl::{Evaluator, Value};
+
+use common::{
+    assert_lines, run_failing_ipl_file, run_ipl_file, run_ipl_file_with_test_libraries, run_repl,
+    source_error,
+};
 
 #[test]
 fn test_while_and_for_loop_with_continue() {
     let output = run_ipl_file("tests/ipl_files/loops.ipl");
-    let expected = vec!["7", "3", "1", "3", "5", "1", "2"];
+    let expected = vec!["7", "3", "1", "3", "5", "1", "2", "h", "é"];
     assert_lines(&output, expected);
+
+    let error = source_error("for x in 5\n    out(x)");
+    assert!(error.contains("TypeError: Cannot iterate over an int"));
 }
 
 #[test]
@@ -26,7 +34,7 @@ fn test_class_inheritance() {
 fn test_math_output() {
     let output = run_ipl_file("tests/ipl_files/math.ipl");
     // Check for expected math operation results
-    let expected = vec!["15", "5", "50", "10", "3.14159265358979"]; // Cant do more, because they are cut of
+    let expected = vec!["15", "5", "50", "10.0", "3.14159265358979"]; // Cant do more, because they are cut of
     assert_lines(&output, expected);
 }
 
@@ -56,8 +64,442 @@ fn test_libraries() {
     assert_lines(&output, expected);
 }
 #[test]
+fn test_library_callbacks() {
+    let (output, error) = run_ipl_file_with_test_libraries("tests/ipl_files/library_callbacks.ipl");
+    let expected = vec!["30", "11", "9", "11", "6", "10"];
+    assert_lines(&output, expected);
+    assert!(error.contains("library_callbacks.ipl:21 in fails: return missing"));
+    assert!(error.contains("NameError: Unknown variable missing"));
+}
+#[test]
 fn test_indexing() {
     let output = run_ipl_file("tests/ipl_files/indexing.ipl");
     assert!(output.contains("1"));
-    assert!(output.contains("[2, 3, 4, 5]"));
+    assert!(output.contains("[2, 3, 4]"));
+}
+#[test]
+fn test_nested_blocks() {
+    let output = run_ipl_file("tests/ipl_files/nesting.ipl");
+    let expected = vec!["0", "1", "10", "11", "20", "21", "4", "8"];
+    assert_lines(&output, expected);
+}
+#[test]
+fn test_runtime_error_is_reported() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/errors.ipl");
+    assert_lines(&output, vec!["before"]);
+    assert!(error.contains("NameError: Unknown variable undefined_variable"));
+    assert!(error.contains("errors.ipl:2"));
+    assert!(!error.contains("panicked"));
+}
+#[test]
+fn test_error_points_at_column() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/diagnostics.ipl");
+    assert_lines(&output, vec!["before"]);
+    assert!(error.contains("TypeError: Unsupported types for +: int and string"));
+    assert!(error.contains("diagnostics.ipl:6:5"));
+    assert!(error.contains("6 | out(numbers[0] + \"text\")"));
+    assert!(error.contains(&format!("  |     {}", "^".repeat(19))));
+}
+#[test]
+fn test_error_in_multiline_string_statement() {
+    let (_, error) = run_failing_ipl_file("tests/ipl_files/multiline_diagnostics.ipl");
+    assert!(error.contains("multiline_diagnostics.ipl:3 in show: out(\"\"\"a\n"));
+    assert!(error.contains("multiline_diagnostics.ipl:4:8"));
+    assert!(error.contains("4 | b\"\"\" + missing)"));
+    assert!(error.ends_with(&format!("  |        {}", "^".repeat(7))));
+}
+#[test]
+fn test_traceback() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/traceback.ipl");
+    assert_lines(&output, vec!["start"]);
+    assert!(error.contains("Traceback (most recent call last):"));
+    assert!(error.contains("traceback.ipl:12 in <module>: d.bark(2)"));
+    assert!(error.contains("traceback.ipl:5 in Dog.bark: return helper(times)"));
+    assert!(error.contains("traceback.ipl:8 in helper: return n + missing"));
+    assert!(error.contains("NameError: Unknown variable missing"));
+}
+#[test]
+fn test_repl() {
+    let input = "x = 5\nx + 2\ndef square(n)\n    return n * n\n\nsquare(x)\nmissing\nif x > 3\n    out(\"big\")\nelse\n    out(\"small\")\n\nout(\"still running\")\n";
+    let (output, error) = run_repl(input);
+    // The first line is the greeting
+    let lines: Vec<&str> = output.lines().skip(1).collect();
+    assert_eq!(lines, vec!["7", "25", "big", "still running"]);
+    assert!(error.contains("NameError: Unknown variable missing"));
+}
+#[test]
+fn test_repl_quit() {
+    let (output, error) = run_repl("out(1)\nquit()\nout(2)\n");
+    let lines: Vec<&str> = output.lines().skip(1).collect();
+    assert_eq!(lines, vec!["1"]);
+    assert_eq!(error, "");
+}
+#[test]
+fn test_function_scopes() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/scopes.ipl");
+    assert_lines(&output, vec!["local", "global", "2", "global!", "120"]);
+    assert!(error.contains("NameError: Unknown variable temp"));
+}
+#[test]
+fn test_instances_are_references() {
+    let output = run_ipl_file("tests/ipl_files/references.ipl");
+    assert_lines(&output, vec!["1", "12", "2", "0", "true", "false"]);
+}
+#[test]
+fn test_maps() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/maps.ipl");
+    let expected = vec![
+        "31", "{Tom: 32, Anna: 27, Ben: 19}", "3", "Tom", "32", "Anna", "27", "Ben", "19",
+        "true", "false", "true", "true", "{a: [1, 2], b: true}", "one", "0",
+        "true", "false", "true", "true",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("KeyError: Key Lisa not found"));
+}
+#[test]
+fn test_index_assignment() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/index_assignment.ipl");
+    let expected = vec![
+        "[10, 2, 3, 4, 5]",
+        "[10, 20, 3, 4, 5]",
+        "[10, 20, 7, 8, 9]",
+        "[0, 0, 10, 20, 7, 8, 9]",
+        "[[0, 0], [1, 0]]",
+        "[1, 2, v]",
+        "{list: [1, 5]}",
+        "-1",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("IndexError: Index 10 is out of bounds for length 7"));
+    assert!(error.contains("index_assignment.ipl:40:1"));
+
+    // The list became shorter between reading and writing the item
+    let source = "items = [1, 2, 3]\ndef shrink()\n    items[1:] = []\n    return 1\nitems[2] += shrink()";
+    assert!(source_error(source).contains("IndexError: Index 2 is out of bounds for length 1"));
+}
+#[test]
+fn test_negative_indices_and_steps() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/slicing.ipl");
+    let expected = vec![
+        "5", "4", "[2, 3]", "[1, 2]", "[4, 5]", "[4, 5]", "[2, 3, 4, 5]", "[1, 3, 5]",
+        "[5, 4, 3, 2, 1]", "[4, 3, 2]", "[0, 2, 0, 4, 0]", "[0, 2, 0, 4, 9]",
+        "[2]",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("IndexError: Index 5 is out of bounds for length 5"));
+}
+#[test]
+fn test_expressions_in_lists_and_indices() {
+    let output = run_ipl_file("tests/ipl_files/expressions_in_lists.ipl");
+    let expected = vec![
+        "[1, 4, 6, xy]", "4", "6", "xy", "[4, 6]", "[[1, 2], [3, [4, 5]], []]", "5", "0", "20",
+    ];
+    assert_lines(&output, expected);
+}
+#[test]
+fn test_string_indexing() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/strings.ipl");
+    let expected = vec![
+        "h", "o", "el", "olleh", "8", "ü", "👋", "Grüße", "👋!", "hG", "er",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("TypeError: Cannot assign to items of a string"));
+}
+
+#[test]
+fn test_unary_operators() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/unary.ipl");
+    let expected = vec![
+        "-4", "4", "4", "4", "-8", "6", "-5", "3", "false", "false", "false", "true", "positive",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("TypeError: Unsupported type for unary -: string"));
+}
+
+#[test]
+fn test_and_or_short_circuit() {
+    let source = [
+        "calls = 0",
+        "def touch(result)",
+        "    global calls",
+        "    calls += 1",
+        "    return result",
+        "items = []",
+        "first = len(items) > 0 and items[0]",
+        "[false and touch(true), true or touch(false), true and touch(true), first, calls]",
+    ]
+    .join("\n");
+    let result = Evaluator::new().ev_source(&source).unwrap();
+    assert_eq!(result.to_string_value(), "[false, true, true, false, 1]");
+}
+
+#[test]
+fn test_modulo_floor_division_and_power() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/arithmetic.ipl");
+    let expected = vec![
+        "1", "2", "-2", "1.5", "3", "-4", "1024", "512", "-4", "4", "0.5", "4", "2", "4",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("RuntimeError: Division by zero"));
+
+    for source in ["out(0 ** -1)", "out(0.0 ** -0.5)", "out(pow(0, -2))"] {
+        assert!(source_error(source).contains("RuntimeError: Division by zero"));
+    }
+    assert_eq!(Evaluator::new().ev_source("0 ** 0").unwrap(), Value::Int(1));
+}
+
+#[test]
+fn test_compound_assignment() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/compound_assignment.ipl");
+    let expected = vec![
+        "15",
+        "12",
+        "24",
+        "6.0",
+        "3.0",
+        "27.0",
+        "2.0",
+        "IPL!",
+        "5",
+        "4",
+        "[11, 2, 6]",
+        "[[0, 0], [7, 0]]",
+        "{ada: 37}",
+        "[6, 2, 3]",
+        "1",
+        "[[1], [12]]",
+        "2",
+        "3",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("NameError: Unknown variable missing"));
+}
+
+#[test]
+fn test_integers_and_floats() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/integers.ipl");
+    let expected = vec![
+        "10.0",
+        "1.0",
+        "3",
+        "3.0",
+        "9223372036854775807",
+        "1.5",
+        "true",
+        "true",
+        "-9223372036854775808",
+        "0",
+        "a",
+        "1",
+        "3",
+        "-3",
+        "43",
+        "2.0",
+        "1.5",
+        "6",
+        "5",
+        "2.5",
+        "c",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("TypeError: Indices must be integers, not float"));
+
+    // Ints and floats are compared exactly, 2^53 + 1 is not rounded to the float next to it
+    let source = "big = 9007199254740993\n[big == 9007199254740992.0, big > 9007199254740992.0, 2 == 2.0, -3 > -3.5, 9223372036854775807 < 9223372036854775808.0]";
+    let result = Evaluator::new().ev_source(source).unwrap();
+    assert_eq!(result.to_string_value(), "[false, true, true, true, true]");
+
+    // Names that Rust would parse as floats are still variables
+    let result = Evaluator::new().ev_source("inf = 2\nnan = 3\ninf * nan").unwrap();
+    assert_eq!(result, Value::Int(6));
+    assert!(source_error("out(infinity)").contains("NameError: Unknown variable infinity"));
+}
+
+#[test]
+fn test_integer_overflow() {
+    let too_large = "OverflowError: Integer result of";
+    assert!(source_error("out(5 ** 100)").contains(too_large));
+    assert!(source_error("x = 9223372036854775807
+x += 1").contains(too_large));
+    assert!(source_error("out(-(-9223372036854775807 - 1))").contains(too_large));
+    assert!(source_error("out(pow(2, 64))").contains(too_large));
+    let error = source_error("out(value(-9223372036854775807 - 1))");
+    assert!(error.contains("OverflowError: Integer result of value is too large"));
+    let error = source_error("out(int(10.0 ** 400))");
+    assert!(error.contains("OverflowError: Float inf is too large in magnitude for an int"));
+    let error = source_error("out(int(-(10.0 ** 400)))");
+    assert!(error.contains("OverflowError: Float -inf is too large in magnitude for an int"));
+    let error = source_error("out(99999999999999999999)");
+    assert!(error.contains("OverflowError: Integer literal 99999999999999999999 is too large"));
+}
+
+#[test]
+fn test_string_escapes_and_multiline_strings() {
+    let output = run_ipl_file("tests/ipl_files/string_escapes.ipl");
+    let expected = vec![
+        "She said \"hi\"",
+        "It's fine",
+        "tab\there",
+        "two",
+        "lines",
+        "back\\slash",
+        "ü and 👋",
+        "3",
+        "Roses are red,",
+        "    violets are blue,",
+        "'single' and \"double\" quotes",
+        "65",
+        "indented",
+        "  block",
+        "after",
+    ];
+    assert_lines(&output, expected);
+
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/unknown_escape.ipl");
+    assert!(output.is_empty());
+    assert!(error.contains("SyntaxError: Unknown escape sequence \\q"));
+    assert!(error.contains("unknown_escape.ipl:2:14"));
+}
+
+#[test]
+fn test_format_strings() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/fstrings.ipl");
+    let expected = vec![
+        "Hello Ada, you are 37",
+        "Ada has 3 letters",
+        "first: 1, last: 3, slice: [2, 3], map: 36",
+        "{braces} and Ada",
+        "tab\tAda",
+        "36",
+        "3.14",
+        "3",
+        "36.0",
+        "[   Ada]",
+        "[Ada   ]",
+        "[**Ada**]",
+        "[   36]",
+        "[-0036]",
+        "[   3.142]",
+        "Ad",
+        "1:   1",
+        "2:   3",
+        "3:   6",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("TypeError: Invalid format spec 'd' for float"));
+}
+
+#[test]
+fn test_comments_and_docstrings() {
+    let output = run_ipl_file("tests/ipl_files/comments.ipl");
+    let expected = vec![
+        "Item #3",
+        "# not a comment",
+        "quote \" # still a string",
+        "3 # items",
+        "first # line",
+        "second",
+        "3",
+    ];
+    assert_lines(&output, expected);
+
+    // Docstrings are not evaluated, so a function with only a docstring returns nothing
+    let source = "def f()\n    \"\"\"Only a docstring\"\"\"\nf()";
+    assert_eq!(Evaluator::new().ev_source(source).unwrap(), Value::None);
+    // Other strings are expressions like any other
+    assert_eq!(Evaluator::new().ev_source("\"hi\"").unwrap(), Value::from("hi"));
+}
+
+#[test]
+fn test_functions_as_values() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/functions_as_values.ipl");
+    let expected = vec![
+        "[2, 4, 6, 8]",
+        "[2, 4]",
+        "[#1, #2, #3, #4]",
+        "10",
+        "16",
+        "6",
+        "9",
+        "no parameters",
+        "[1, 2, 3]",
+        "4",
+        "2",
+        "clicked at 2",
+        "1",
+        "<function double>",
+        "<function <lambda>>",
+        "<function len>",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("TypeError: Cannot call a list"));
+}
+
+#[test]
+fn test_closures() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/closures.ipl");
+    let expected = vec![
+        "3",
+        "1",
+        "6",
+        "11",
+        "calling with 3",
+        "9",
+        "after",
+        "10",
+        "2",
+        "1",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("NameError: Unknown function helper"));
+}
+
+#[test]
+fn test_keyword_arguments() {
+    let (output, error) = run_failing_ipl_file("tests/ipl_files/keyword_arguments.ipl");
+    let expected = vec![
+        "Hi, Ann!",
+        "Hello, Ann!",
+        "Hi, Bob!",
+        "Hey, Cid!",
+        "Yo, Dan!",
+        "11",
+        "0",
+        "5",
+        "3",
+        "> hello!",
+        "Bye, Eve!",
+    ];
+    assert_lines(&output, expected);
+    assert!(error.contains("ArgumentError: greet() got multiple values for argument 'name'"));
+}
+
+#[test]
+fn test_keyword_argument_errors() {
+    let greet = "def greet(name, greeting=\"Hi\")\n    return greeting\n";
+    let error = source_error(&format!("{}greet(\"Ann\", mood=\"happy\")", greet));
+    assert!(error.contains("ArgumentError: greet() got an unknown keyword argument 'mood'"));
+    let error = source_error(&format!("{}greet(greeting=\"Hey\")", greet));
+    assert!(error.contains("ArgumentError: Missing argument 'name' for greet()"));
+    let error = source_error(&format!("{}greet(name=\"Ann\", \"Hey\")", greet));
+    assert!(error.contains("SyntaxError: Positional argument after keyword arguments"));
+    let error = source_error(&format!("{}greet(name=\"Ann\", name=\"Bob\")", greet));
+    assert!(error.contains("SyntaxError: Duplicate keyword argument name"));
+    let error = source_error("len(collection=[1, 2])");
+    assert!(error.contains("ArgumentError: Function 'len' does not take keyword arguments"));
+    let error = source_error("def greet(greeting=\"Hi\", name)\n    return name");
+    let message = "SyntaxError: Parameter name without a default follows one with a default";
+    assert!(error.contains(message));
+
+    let (_, error) = run_ipl_file_with_test_libraries("tests/ipl_files/library_keyword_error.ipl");
+    assert!(error.contains("ArgumentError: scale() got an unknown keyword argument 'factor'"));
+}
+#[test]
+fn test_recursion_limit() {
+    // Run by the binary, which gives the interpreter a stack deep enough for the limit
+    let (output, error) = run_ipl_file_with_test_libraries("tests/ipl_files/recursion_limit.ipl");
+    assert_lines(&output, vec!["done"]);
+    assert!(error.contains("recursion_limit.ipl:10 in <module>: forever(0)"));
+    assert!(error.contains("recursion_limit.ipl:7 in forever: return forever(n + 1)"));
+    assert!(error.contains("RuntimeError: maximum recursion depth exceeded"));
 }